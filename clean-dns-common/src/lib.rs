@@ -1,11 +1,393 @@
 #![no_std]
 
+/// Drop responses whose IP id is 0.
+pub const POLICY_IP_ID_ZERO: u32 = 1 << 0;
+/// Drop responses with the Don't fragment flag.
+pub const POLICY_DONT_FRAGMENT: u32 = 1 << 1;
+/// Drop single answer responses with the Authoritative mark.
+pub const POLICY_AUTHORITATIVE: u32 = 1 << 2;
+/// Drop single answer responses whose answer TYPE doesn't match the QTYPE.
+pub const POLICY_ANSWER_TYPE: u32 = 1 << 3;
+/// Drop single answer responses whose answer NAME isn't a pointer to the question.
+pub const POLICY_ANSWER_NAME: u32 = 1 << 4;
+/// Drop single answer responses without an EDNS0 OPT record when the query had one.
+pub const POLICY_REQUIRE_EDNS: u32 = 1 << 5;
+/// Drop single answer responses whose TTL is in one of the policy TTL ranges.
+pub const POLICY_ANSWER_TTL: u32 = 1 << 6;
+/// Drop responses whose IP TTL is outside of the policy IP TTL range.
+pub const POLICY_IP_TTL: u32 = 1 << 7;
+/// Skip the checks, only report the responses so userspace can learn the upstream's fingerprint.
+/// Answers in the bogus sets are still dropped.
+pub const POLICY_LEARN: u32 = 1 << 8;
+/// Drop responses that don't match a query recorded on egress.
+pub const POLICY_UNSOLICITED: u32 = 1 << 9;
+/// Once a response to a recorded query passed, drop the later ones.
+pub const POLICY_DROP_DUPLICATES: u32 = 1 << 10;
+/// Drop responses to a recorded query arriving faster than the policy minimum RTT.
+pub const POLICY_MIN_RTT: u32 = 1 << 11;
+/// Randomize the QNAME case of the queries (DNS 0x20) and drop responses not echoing it.
+pub const POLICY_0X20: u32 = 1 << 12;
+/// Add a client cookie to the EDNS0 queries and drop responses not echoing it with a server cookie.
+pub const POLICY_COOKIES: u32 = 1 << 13;
+/// Rewrite the responses the checks drop into empty truncated replies, making the client retry over TCP.
+pub const POLICY_TRUNCATE: u32 = 1 << 14;
+/// Drop TCP RST segments whose IP TTL, IP id or window don't match the ones of their flow.
+pub const POLICY_TCP_RST: u32 = 1 << 15;
+/// Checks enabled for upstreams without an explicit policy.
+pub const POLICY_DEFAULT: u32 = POLICY_IP_ID_ZERO | POLICY_DONT_FRAGMENT | POLICY_AUTHORITATIVE;
+
+/// The packet was not dropped.
+pub const REASON_NONE: u32 = 0;
+/// IP id is 0.
+pub const REASON_IP_ID_ZERO: u32 = 1;
+/// IP flag is 0x40 (Don't fragment).
+pub const REASON_DONT_FRAGMENT: u32 = 2;
+/// DNS flag has the Authoritative mark.
+pub const REASON_AUTHORITATIVE: u32 = 3;
+/// The A/AAAA answer is in the bogus answer set.
+pub const REASON_BOGUS_ANSWER: u32 = 4;
+/// The answer TYPE doesn't match the QTYPE.
+pub const REASON_ANSWER_TYPE: u32 = 5;
+/// The answer NAME isn't a pointer to the question.
+pub const REASON_ANSWER_NAME: u32 = 6;
+/// There is no EDNS0 OPT record.
+pub const REASON_MISSING_OPT: u32 = 7;
+/// The answer TTL is in a forged TTL range.
+pub const REASON_ANSWER_TTL: u32 = 8;
+/// The IP TTL isn't the one expected from the upstream.
+pub const REASON_IP_TTL: u32 = 9;
+/// No query was sent to the upstream with this port and id.
+pub const REASON_UNSOLICITED: u32 = 10;
+/// The question isn't the one of the query.
+pub const REASON_QNAME_MISMATCH: u32 = 11;
+/// A response to the same query already passed.
+pub const REASON_DUPLICATE: u32 = 12;
+/// The response arrived faster than the upstream can answer.
+pub const REASON_TOO_FAST: u32 = 13;
+/// The QNAME case isn't the randomized one of the query.
+pub const REASON_CASE_MISMATCH: u32 = 14;
+/// The response doesn't echo the client cookie of the query.
+pub const REASON_BAD_COOKIE: u32 = 15;
+/// The RST IP TTL isn't the one of the flow.
+pub const REASON_RST_IP_TTL: u32 = 16;
+/// The RST IP id doesn't follow the ones of the flow.
+pub const REASON_RST_IP_ID: u32 = 17;
+/// The RST window isn't 0 nor the one of the flow.
+pub const REASON_RST_WINDOW: u32 = 18;
+/// Conntrack has no outgoing flow the response belongs to.
+pub const REASON_NO_CONNTRACK: u32 = 19;
+
+/// The single answer is followed by an EDNS0 OPT record.
+pub const EVENT_FLAG_OPT: u32 = 1 << 0;
+/// The single answer TYPE can answer the QTYPE (see the `answer-type` check).
+pub const EVENT_FLAG_ANSWER_TYPE: u32 = 1 << 1;
+/// The single answer NAME is a pointer to the question.
+pub const EVENT_FLAG_ANSWER_NAME: u32 = 1 << 2;
+/// The response has a single answer and no authority, it was parsed.
+pub const EVENT_FLAG_SINGLE_ANSWER: u32 = 1 << 3;
+/// The response matches a query recorded on egress.
+pub const EVENT_FLAG_MATCHED: u32 = 1 << 4;
+/// The dropped response was passed on as an empty truncated reply instead.
+pub const EVENT_FLAG_TRUNCATED: u32 = 1 << 5;
+/// The packet is a TCP RST, not a dns response.
+pub const EVENT_FLAG_TCP_RST: u32 = 1 << 6;
+/// Conntrack has the outgoing flow the response belongs to.
+pub const EVENT_FLAG_CONNTRACK: u32 = 1 << 7;
+/// The matched query carried an EDNS0 OPT record.
+pub const EVENT_FLAG_EDNS_QUERY: u32 = 1 << 8;
+
+pub fn reason_str(reason: u32) -> &'static str {
+    match reason {
+        REASON_NONE => "none",
+        REASON_IP_ID_ZERO => "ip-id-zero",
+        REASON_DONT_FRAGMENT => "dont-fragment",
+        REASON_AUTHORITATIVE => "authoritative",
+        REASON_BOGUS_ANSWER => "bogus-answer",
+        REASON_ANSWER_TYPE => "answer-type",
+        REASON_ANSWER_NAME => "answer-name",
+        REASON_MISSING_OPT => "missing-opt",
+        REASON_ANSWER_TTL => "answer-ttl",
+        REASON_IP_TTL => "ip-ttl",
+        REASON_UNSOLICITED => "unsolicited",
+        REASON_QNAME_MISMATCH => "qname-mismatch",
+        REASON_DUPLICATE => "duplicate",
+        REASON_TOO_FAST => "too-fast",
+        REASON_CASE_MISMATCH => "case-mismatch",
+        REASON_BAD_COOKIE => "bad-cookie",
+        REASON_RST_IP_TTL => "rst-ip-ttl",
+        REASON_RST_IP_ID => "rst-ip-id",
+        REASON_RST_WINDOW => "rst-window",
+        REASON_NO_CONNTRACK => "no-conntrack",
+        _ => "unknown",
+    }
+}
+
+pub const MAX_TTL_RANGES: usize = 4;
+
+/// An inclusive range of TTL values.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct TtlRange {
+    pub min: u32,
+    pub max: u32,
+}
+
+/// The checks applied to an upstream, the value of `BLOCKLIST`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct UpstreamPolicy {
+    pub flags: u32,
+    /// answer TTL ranges used by injected responses
+    pub ttl_ranges: [TtlRange; MAX_TTL_RANGES],
+    pub ttl_range_count: u32,
+    /// IP TTL range of the legitimate responses
+    pub ip_ttl: TtlRange,
+    /// the fastest the upstream can answer, in microseconds
+    pub min_rtt_us: u32,
+}
+
+impl UpstreamPolicy {
+    pub const fn new(flags: u32) -> Self {
+        UpstreamPolicy {
+            flags,
+            ttl_ranges: [TtlRange { min: 0, max: 0 }; MAX_TTL_RANGES],
+            ttl_range_count: 0,
+            ip_ttl: TtlRange { min: 0, max: 0 },
+            min_rtt_us: 0,
+        }
+    }
+
+    /// Whether `ttl` is in one of the forged TTL ranges.
+    #[inline(always)]
+    pub fn forged_ttl(&self, ttl: u32) -> bool {
+        for i in 0..MAX_TTL_RANGES {
+            if i >= self.ttl_range_count as usize {
+                break;
+            }
+            let range = &self.ttl_ranges[i];
+            if ttl >= range.min && ttl <= range.max {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Version of the layout of the types shared with userspace, to bump whenever
+/// one of them changes, it only ever goes up. Fields appended to `PacketLog`
+/// don't need a bump, the events of an older object are still decoded.
+pub const ABI_VERSION: u32 = 6;
+/// Oldest version whose layout only lacks fields appended to `PacketLog` since,
+/// objects from it on are still loaded.
+pub const ABI_MIN_VERSION: u32 = 5;
+/// Name of the object section holding the `Abi` of the eBPF programs.
+pub const ABI_SECTION: &str = "clean_dns_abi";
+
+/// What the eBPF object was built against, checked by userspace before loading it.
 #[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Abi {
+    pub version: u32,
+    pub packet_log_size: u32,
+    pub upstream_policy_size: u32,
+}
+
+impl PacketLog {
+    pub const HEADER: EventHeader = EventHeader::new(core::mem::size_of::<PacketLog>());
+}
+
+impl Abi {
+    pub const CURRENT: Abi = Abi {
+        version: ABI_VERSION,
+        packet_log_size: core::mem::size_of::<PacketLog>() as u32,
+        upstream_policy_size: core::mem::size_of::<UpstreamPolicy>() as u32,
+    };
+}
+
+/// "CLND", the first bytes of every event.
+pub const EVENT_MAGIC: u32 = 0x434c_4e44;
+/// Version of the events, only bumped for changes older readers can't skip.
+///
+/// Fields are only ever appended to an event, its `len` tells which ones an
+/// older object didn't have yet.
+pub const EVENT_VERSION: u16 = 1;
+
+/// The header starting every event output to `EVENTS`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct EventHeader {
+    pub magic: u32,
+    pub version: u16,
+    /// length of the whole event, header included
+    pub len: u16,
+}
+
+impl EventHeader {
+    pub const fn new(len: usize) -> Self {
+        EventHeader {
+            magic: EVENT_MAGIC,
+            version: EVENT_VERSION,
+            len: len as u16,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
 pub struct PacketLog {
+    pub header: EventHeader,
     pub ipv4_src_addr: u32,
     pub ipv4_dst_addr: u32,
     pub action: u32,
+    pub reason: u32,
+    /// TTL of the single answer, 0 if it wasn't parsed
+    pub answer_ttl: u32,
+    pub ip_ttl: u32,
+    pub ip_id: u32,
+    pub ip_frag_off: u32,
+    /// the 16 bit dns flags
+    pub dns_flags: u32,
+    /// `EVENT_FLAG_*`
+    pub flags: u32,
+    /// time since the matching query was sent in microseconds, 0 if unmatched
+    pub rtt_us: u32,
+    /// events of the same upstream and reason suppressed since the previous one
+    pub suppressed: u32,
+    /// length of the frame
+    pub pkt_len: u32,
+    /// bytes of the frame following the event, see `CAPTURE`
+    pub cap_len: u32,
+    pub _pad: u32,
+    /// `bpf_ktime_get_ns` when the packet arrived, 0 from older objects
+    pub ktime_ns: u64,
+}
+
+/// The key of `QUERIES`, a query sent to an upstream.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct QueryKey {
+    pub client_addr: u32,
+    pub upstream_addr: u32,
+    pub client_port: u16,
+    pub txid: u16,
 }
 
-#[cfg(feature = "user")]
+/// A response to the query already passed.
+pub const QUERY_FLAG_ANSWERED: u32 = 1 << 0;
+/// The QNAME case of the query was randomized.
+pub const QUERY_FLAG_0X20: u32 = 1 << 1;
+/// A client cookie was added to the query.
+pub const QUERY_FLAG_COOKIE: u32 = 1 << 2;
+/// The query carried an EDNS0 OPT record, upstreams only echo one then.
+pub const QUERY_FLAG_EDNS: u32 = 1 << 3;
+
+/// Length of a DNS client cookie (RFC 7873).
+pub const COOKIE_LEN: usize = 8;
+
+/// The value of `QUERIES`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct QueryInfo {
+    /// FNV-1a of the lowercased QNAME
+    pub qname_hash: u32,
+    /// FNV-1a of the QNAME as sent
+    pub qname_case_hash: u32,
+    /// `bpf_ktime_get_ns` when the query was sent
+    pub timestamp: u64,
+    /// the letters whose case was flipped by 0x20
+    pub case_mask: u64,
+    /// `QUERY_FLAG_*`
+    pub flags: u32,
+    /// the client cookie added to the query
+    pub cookie: [u8; COOKIE_LEN],
+}
+
+/// The key of `TCP_FLOWS`, a TCP connection to an upstream.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct FlowKey {
+    pub client_addr: u32,
+    pub upstream_addr: u32,
+    pub client_port: u16,
+    pub upstream_port: u16,
+}
+
+/// The value of `TCP_FLOWS`, what the upstream's segments of the flow look like.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct FlowInfo {
+    /// IP TTL of the first segment, the segments with another one are ignored
+    pub ip_ttl: u32,
+    /// IP id of the last segment
+    pub ip_id: u32,
+    /// window of the last segment
+    pub window: u32,
+}
+
+/// Most bytes of a frame captured along with its event.
+pub const MAX_CAPTURE: u32 = 512;
+
+/// The value of `CAPTURE`, how many bytes of each frame follow its event.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct CaptureConfig {
+    /// for the packets given a verdict
+    pub verdict_len: u32,
+    /// for the packets passed without a verdict
+    pub pass_len: u32,
+}
+
+/// The value of `EVENT_RATE`, the token bucket limiting the events of each
+/// upstream and reason.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct EventRate {
+    /// nanoseconds of credit an event costs, 0 for no limit
+    pub cost_ns: u64,
+    /// the most credit a bucket can save up, the burst of events it allows
+    pub burst_ns: u64,
+}
+
+/// The key of `EVENT_BUCKETS`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct RateKey {
+    pub upstream: u32,
+    pub reason: u32,
+}
+
+/// The value of `EVENT_BUCKETS`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct TokenBucket {
+    /// nanoseconds of credit left
+    pub credit_ns: u64,
+    /// `bpf_ktime_get_ns` when the credit was last refilled
+    pub refilled_ns: u64,
+    /// events suppressed since the last one output
+    pub suppressed: u32,
+    pub _pad: u32,
+}
+
+/// The key of `IP_TTL_STATS`, counting the responses of an upstream per IP TTL.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct IpTtlKey {
+    pub upstream: u32,
+    pub ttl: u32,
+}
+
+#[cfg(feature = "userspace")]
 unsafe impl aya::Pod for PacketLog {}
+
+#[cfg(feature = "userspace")]
+unsafe impl aya::Pod for UpstreamPolicy {}
+
+#[cfg(feature = "userspace")]
+unsafe impl aya::Pod for IpTtlKey {}
+
+#[cfg(feature = "userspace")]
+unsafe impl aya::Pod for EventRate {}
+
+#[cfg(feature = "userspace")]
+unsafe impl aya::Pod for CaptureConfig {}
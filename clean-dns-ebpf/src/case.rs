@@ -0,0 +1,121 @@
+use aya_bpf::{
+    bindings::BPF_F_MARK_MANGLED_0,
+    helpers::bpf_get_prandom_u32,
+    programs::{SkBuffContext, XdpContext},
+};
+
+use crate::{csum, packet::Packet, ptr_at};
+
+// only the first letters are randomized, the mask of flipped letters is a u64
+const MAX_LETTERS: usize = 64;
+// same bound as the hashed QNAME
+const MAX_QNAME_LEN: usize = 128;
+
+/// Randomize the case of the letters of the QNAME starting at `offset`
+/// (DNS 0x20), returning the mask of the flipped letters.
+#[inline(always)]
+pub fn randomize(ctx: &mut SkBuffContext, udp: usize, offset: usize) -> Result<u64, ()> {
+    flip_case(ctx, udp, offset, None)
+}
+
+/// Flip the letters of the QNAME starting at `offset` set in `mask`, giving a
+/// retransmitted query the case the first transmission was sent with.
+#[inline(always)]
+pub fn reapply(ctx: &mut SkBuffContext, udp: usize, offset: usize, mask: u64) -> Result<u64, ()> {
+    flip_case(ctx, udp, offset, Some(mask))
+}
+
+// flip the letters set in `fixed`, random ones without it
+#[inline(always)]
+fn flip_case(
+    ctx: &mut SkBuffContext,
+    udp: usize,
+    offset: usize,
+    fixed: Option<u64>,
+) -> Result<u64, ()> {
+    let mut mask = 0u64;
+    let mut random = 0u32;
+    let mut letters = 0;
+    let mut label = 0;
+    for i in 0..MAX_QNAME_LEN {
+        let c: u8 = ctx.read(offset + i)?;
+        if i == label {
+            if c == 0 {
+                return Ok(mask);
+            }
+            if c & 0xc0 != 0 {
+                return Err(());
+            }
+            label = i + 1 + c as usize;
+            continue;
+        }
+        if !c.is_ascii_alphabetic() || letters >= MAX_LETTERS {
+            continue;
+        }
+        let flip = match fixed {
+            Some(fixed) => fixed & (1 << letters) != 0,
+            None => {
+                if letters % 32 == 0 {
+                    random = unsafe { bpf_get_prandom_u32() };
+                }
+                (random >> (letters % 32)) & 1 != 0
+            }
+        };
+        if flip {
+            let flipped = c ^ 0x20;
+            ctx.store(offset + i, &flipped, 0).map_err(|_| ())?;
+            // the kernel wants the words as they are in the packet
+            let word = |c: u8| csum::byte_word(offset + i - udp, c).to_be() as u64;
+            ctx.l4_csum_replace(
+                udp + 6,
+                word(c),
+                word(flipped),
+                BPF_F_MARK_MANGLED_0 as u64 | 2,
+            )
+            .map_err(|_| ())?;
+            mask |= 1 << letters;
+        }
+        letters += 1;
+    }
+    Err(())
+}
+
+/// Flip back the letters of the QNAME starting at `offset` set in `mask`, so
+/// the client sees the case it asked with.
+#[inline(always)]
+pub fn restore(ctx: &XdpContext, udp: usize, offset: usize, mask: u64) -> Result<(), ()> {
+    let check = unsafe { ptr_at::<[u8; 2]>(ctx, udp + 6)? as *mut [u8; 2] };
+    let mut letters = 0;
+    let mut label = 0;
+    for i in 0..MAX_QNAME_LEN {
+        let c = unsafe { ptr_at::<u8>(ctx, offset + i)? as *mut u8 };
+        let old = unsafe { *c };
+        if i == label {
+            if old == 0 {
+                return Ok(());
+            }
+            if old & 0xc0 != 0 {
+                return Err(());
+            }
+            label = i + 1 + old as usize;
+            continue;
+        }
+        if !old.is_ascii_alphabetic() || letters >= MAX_LETTERS {
+            continue;
+        }
+        if mask & (1 << letters) != 0 {
+            let new = old ^ 0x20;
+            unsafe {
+                *c = new;
+                *check = csum::udp_replace2(
+                    u16::from_be_bytes(*check),
+                    csum::byte_word(offset + i - udp, old),
+                    csum::byte_word(offset + i - udp, new),
+                )
+                .to_be_bytes();
+            }
+        }
+        letters += 1;
+    }
+    Err(())
+}
@@ -0,0 +1,75 @@
+use aya_bpf::{bindings::xdp_md, programs::XdpContext};
+
+use crate::constants::IPPROTO_UDP;
+
+const BPF_F_CURRENT_NETNS: i32 = -1;
+const ENOENT: i32 = 2;
+
+#[repr(C)]
+struct bpf_sock_tuple_ipv4 {
+    saddr: u32,
+    daddr: u32,
+    sport: u16,
+    dport: u16,
+}
+
+#[repr(C)]
+struct bpf_ct_opts {
+    netns_id: i32,
+    error: i32,
+    l4proto: u8,
+    reserved: [u8; 3],
+}
+
+#[repr(C)]
+struct nf_conn {
+    _private: [u8; 0],
+}
+
+// netfilter conntrack kfuncs, resolved against the kernel BTF at load time
+extern "C" {
+    fn bpf_xdp_ct_lookup(
+        ctx: *mut xdp_md,
+        tuple: *mut bpf_sock_tuple_ipv4,
+        tuple_sz: u32,
+        opts: *mut bpf_ct_opts,
+        opts_sz: u32,
+    ) -> *mut nf_conn;
+    fn bpf_ct_release(ct: *mut nf_conn);
+}
+
+/// Whether conntrack knows the UDP flow of the packet, `None` if the lookup
+/// itself failed. Addresses and ports are in network byte order.
+#[inline(always)]
+pub fn udp_flow(ctx: &XdpContext, saddr: u32, daddr: u32, sport: u16, dport: u16) -> Option<bool> {
+    let mut tuple = bpf_sock_tuple_ipv4 {
+        saddr,
+        daddr,
+        sport,
+        dport,
+    };
+    let mut opts = bpf_ct_opts {
+        netns_id: BPF_F_CURRENT_NETNS,
+        error: 0,
+        l4proto: IPPROTO_UDP as u8,
+        reserved: [0; 3],
+    };
+    let ct = unsafe {
+        bpf_xdp_ct_lookup(
+            ctx.ctx,
+            &mut tuple,
+            core::mem::size_of::<bpf_sock_tuple_ipv4>() as u32,
+            &mut opts,
+            core::mem::size_of::<bpf_ct_opts>() as u32,
+        )
+    };
+    if !ct.is_null() {
+        unsafe { bpf_ct_release(ct) };
+        return Some(true);
+    }
+    if opts.error == -ENOENT {
+        Some(false)
+    } else {
+        None
+    }
+}
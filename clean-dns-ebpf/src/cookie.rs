@@ -0,0 +1,148 @@
+use aya_bpf::{
+    bindings::{BPF_F_MARK_MANGLED_0, BPF_F_PSEUDO_HDR},
+    helpers::bpf_skb_change_tail,
+    programs::SkBuffContext,
+};
+use clean_dns_common::COOKIE_LEN;
+
+use crate::{
+    constants::ETH_HLEN,
+    csum,
+    dns::{self, DNS_HLEN, TYPE_OPT},
+    packet::Packet,
+};
+
+const OPTION_COOKIE: u16 = 10;
+// a server cookie is 8 to 32 bytes
+const MIN_SERVER_COOKIE: u16 = 8;
+const MAX_SERVER_COOKIE: u16 = 32;
+// options looked at before giving up on finding the cookie
+const MAX_OPTIONS: usize = 8;
+
+const OPTION_LEN: usize = 4 + COOKIE_LEN;
+
+/// Add the client `cookie` to the query whose dns packet starts at `dns`,
+/// returning whether the query had a layout it could be added to.
+///
+/// Only a single question query ending with a lone empty OPT record is
+/// touched. A client that didn't send EDNS0 must not get an OPT record back,
+/// nor a payload size it never advertised.
+#[inline(always)]
+pub fn add(
+    ctx: &mut SkBuffContext,
+    udp: usize,
+    dns: usize,
+    cookie: &[u8; COOKIE_LEN],
+) -> Result<bool, ()> {
+    let header: [u8; DNS_HLEN] = ctx.read(dns)?;
+    if header[4..] != [0, 1, 0, 0, 0, 0, 0, 1] {
+        return Ok(false);
+    }
+    let end = dns::parse_question(ctx, dns)?.end;
+    let opt = dns::parse_answer(ctx, end)?;
+    if opt.rtype != TYPE_OPT || opt.rdlength != 0 || opt.rdata != ctx.len() as usize {
+        return Ok(false);
+    }
+    let mut option = [0u8; OPTION_LEN];
+    option[..4].copy_from_slice(&[0, OPTION_COOKIE as u8, 0, COOKIE_LEN as u8]);
+    option[4..].copy_from_slice(cookie);
+    append(ctx, udp, &option)?;
+    set_u16(ctx, udp, opt.rdata - 2, 0, OPTION_LEN as u16)?;
+    Ok(true)
+}
+
+/// Whether the OPT record of the response starting at `dns` echoes the client
+/// `cookie` along with a server cookie.
+///
+/// Fails when the response couldn't be parsed far enough to tell.
+#[inline(always)]
+pub fn verify<P: Packet>(ctx: &P, dns: usize, cookie: &[u8; COOKIE_LEN]) -> Result<bool, ()> {
+    let opt = match dns::find_packet_opt(ctx, dns)? {
+        Some(opt) => opt,
+        None => return Ok(false),
+    };
+    let end = opt.rdata + opt.rdlength as usize;
+    let mut offset = opt.rdata;
+    for _ in 0..MAX_OPTIONS {
+        if offset + 4 > end {
+            return Ok(false);
+        }
+        let option: [u8; 4] = ctx.read(offset)?;
+        let code = u16::from_be_bytes([option[0], option[1]]);
+        let length = u16::from_be_bytes([option[2], option[3]]);
+        if code == OPTION_COOKIE {
+            if length < COOKIE_LEN as u16 + MIN_SERVER_COOKIE
+                || length > COOKIE_LEN as u16 + MAX_SERVER_COOKIE
+            {
+                return Ok(false);
+            }
+            let client: [u8; COOKIE_LEN] = ctx.read(offset + 4)?;
+            return Ok(&client == cookie);
+        }
+        offset += 4 + length as usize;
+    }
+    // the cookie may be in the options left
+    Err(())
+}
+
+/// Append `bytes` to the packet, growing the IP and UDP lengths and checksums.
+#[inline(always)]
+fn append<const N: usize>(ctx: &mut SkBuffContext, udp: usize, bytes: &[u8; N]) -> Result<(), ()> {
+    let len = ctx.len() as usize;
+    let ret = unsafe { bpf_skb_change_tail(ctx.skb, (len + N) as u32, 0) };
+    if ret != 0 {
+        return Err(());
+    }
+    ctx.store(len, bytes, 0).map_err(|_| ())?;
+    let sum = csum::sum(len - udp, bytes);
+    ctx.l4_csum_replace(
+        udp + 6,
+        0,
+        sum.to_be() as u64,
+        BPF_F_MARK_MANGLED_0 as u64 | 2,
+    )
+    .map_err(|_| ())?;
+    // tot_len of the ip header
+    let ip = ETH_HLEN as usize;
+    let tot_len = u16::from_be(ctx.read(ip + 2)?);
+    ctx.store(ip + 2, &(tot_len + N as u16).to_be(), 0)
+        .map_err(|_| ())?;
+    ctx.l3_csum_replace(
+        ip + 10,
+        tot_len.to_be() as u64,
+        (tot_len + N as u16).to_be() as u64,
+        2,
+    )
+    .map_err(|_| ())?;
+    // the udp length is both in the pseudo header and in the udp header
+    let udp_len = u16::from_be(ctx.read(udp + 4)?);
+    ctx.l4_csum_replace(
+        udp + 6,
+        udp_len.to_be() as u64,
+        (udp_len + N as u16).to_be() as u64,
+        (BPF_F_MARK_MANGLED_0 | BPF_F_PSEUDO_HDR) as u64 | 2,
+    )
+    .map_err(|_| ())?;
+    set_u16(ctx, udp, udp + 4, udp_len, udp_len + N as u16)
+}
+
+/// Store the big endian `new` at `offset` over `old`, updating the UDP checksum.
+#[inline(always)]
+fn set_u16(
+    ctx: &mut SkBuffContext,
+    udp: usize,
+    offset: usize,
+    old: u16,
+    new: u16,
+) -> Result<(), ()> {
+    ctx.store(offset, &new.to_be(), 0).map_err(|_| ())?;
+    // the kernel wants the words as they are in the packet
+    let word = |value: u16| csum::field_word(offset - udp, value).to_be() as u64;
+    ctx.l4_csum_replace(
+        udp + 6,
+        word(old),
+        word(new),
+        BPF_F_MARK_MANGLED_0 as u64 | 2,
+    )
+    .map_err(|_| ())
+}
@@ -0,0 +1,56 @@
+/// Incrementally update the internet checksum `check` for a 16 bit word
+/// changing from `old` to `new` (RFC 1624), all in host byte order.
+#[inline(always)]
+pub fn replace2(check: u16, old: u16, new: u16) -> u16 {
+    let mut sum = (!check) as u32 + (!old) as u32 + new as u32;
+    sum = (sum & 0xffff) + (sum >> 16);
+    sum = (sum & 0xffff) + (sum >> 16);
+    !(sum as u16)
+}
+
+/// Like `replace2` for an UDP checksum, where 0 means there is no checksum.
+#[inline(always)]
+pub fn udp_replace2(check: u16, old: u16, new: u16) -> u16 {
+    if check == 0 {
+        return 0;
+    }
+    match replace2(check, old, new) {
+        0 => 0xffff,
+        check => check,
+    }
+}
+
+/// The 16 bit word a byte at `offset` bytes from the start of the UDP header
+/// contributes to the checksum, in host byte order.
+#[inline(always)]
+pub fn byte_word(offset: usize, byte: u8) -> u16 {
+    if offset % 2 == 0 {
+        (byte as u16) << 8
+    } else {
+        byte as u16
+    }
+}
+
+/// The 16 bit word a big endian field at `offset` bytes from the start of the
+/// UDP header contributes to the checksum, in host byte order.
+#[inline(always)]
+pub fn field_word(offset: usize, value: u16) -> u16 {
+    if offset % 2 == 0 {
+        value
+    } else {
+        value.swap_bytes()
+    }
+}
+
+/// The folded sum of `bytes` at `offset` bytes from the start of the UDP header,
+/// in host byte order.
+#[inline(always)]
+pub fn sum<const N: usize>(offset: usize, bytes: &[u8; N]) -> u16 {
+    let mut sum = 0u32;
+    for i in 0..N {
+        sum += byte_word(offset + i, bytes[i]) as u32;
+    }
+    sum = (sum & 0xffff) + (sum >> 16);
+    sum = (sum & 0xffff) + (sum >> 16);
+    sum as u16
+}
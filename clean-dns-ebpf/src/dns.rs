@@ -0,0 +1,190 @@
+use crate::packet::Packet;
+
+pub const DNS_HLEN: usize = 12;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_CNAME: u16 = 5;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_DNAME: u16 = 39;
+pub const TYPE_OPT: u16 = 41;
+pub const TYPE_RRSIG: u16 = 46;
+pub const TYPE_ANY: u16 = 255;
+
+// a name has at most 127 labels, but real questions are much shorter and
+// the verifier has to walk every iteration
+const MAX_LABELS: usize = 32;
+// the OPT record is usually the only (or last of a few) additional record
+const MAX_ADDITIONALS: usize = 4;
+// answer and authority records walked to reach the additional section
+const MAX_RECORDS: usize = 16;
+// longest QNAME that is hashed, longer questions aren't tracked and the
+// checks needing the query let their responses through
+const MAX_QNAME_LEN: usize = 128;
+
+const FNV_OFFSET: u32 = 0x811c_9dc5;
+const FNV_PRIME: u32 = 0x0100_0193;
+
+pub struct Question {
+    pub qtype: u16,
+    /// offset of the first byte after the question
+    pub end: usize,
+}
+
+/// compression pointer to the name of the first question
+pub const QUESTION_POINTER: u16 = 0xc000 | DNS_HLEN as u16;
+
+pub struct Qname {
+    /// FNV-1a of the lowercased QNAME
+    pub hash: u32,
+    /// FNV-1a of the QNAME as is
+    pub case_hash: u32,
+    /// offset of the first byte after the QNAME
+    pub end: usize,
+}
+
+pub struct Answer {
+    /// the first two bytes of the NAME
+    pub name: u16,
+    pub rtype: u16,
+    pub ttl: u32,
+    pub rdlength: u16,
+    /// offset of the RDATA
+    pub rdata: usize,
+}
+
+/// Skip a (possibly compressed) domain name starting at `offset`, returning
+/// the offset of the first byte after it.
+#[inline(always)]
+pub fn skip_name<P: Packet>(ctx: &P, mut offset: usize) -> Result<usize, ()> {
+    for _ in 0..MAX_LABELS {
+        let len: u8 = ctx.read(offset)?;
+        // end of name
+        if len == 0 {
+            return Ok(offset + 1);
+        }
+        // compression pointer, always the last part of a name
+        if len & 0xc0 == 0xc0 {
+            return Ok(offset + 2);
+        }
+        offset += 1 + len as usize;
+    }
+    Err(())
+}
+
+/// Hash the uncompressed QNAME starting at `offset`, with and without its case.
+#[inline(always)]
+pub fn hash_qname<P: Packet>(ctx: &P, offset: usize) -> Result<Qname, ()> {
+    let mut hash = FNV_OFFSET;
+    let mut case_hash = FNV_OFFSET;
+    let mut label = 0;
+    for i in 0..MAX_QNAME_LEN {
+        let c: u8 = ctx.read(offset + i)?;
+        if i == label {
+            // end of name
+            if c == 0 {
+                return Ok(Qname {
+                    hash,
+                    case_hash,
+                    end: offset + i + 1,
+                });
+            }
+            // there is nothing to point back to in a question
+            if c & 0xc0 != 0 {
+                return Err(());
+            }
+            label = i + 1 + c as usize;
+        }
+        hash = (hash ^ c.to_ascii_lowercase() as u32).wrapping_mul(FNV_PRIME);
+        case_hash = (case_hash ^ c as u32).wrapping_mul(FNV_PRIME);
+    }
+    Err(())
+}
+
+/// Whether a lone answer of type `rtype` can answer a question of type `qtype`.
+///
+/// A CNAME or DNAME redirects any question, an RRSIG may come first, and ANY
+/// is answered with whatever the upstream picks (a single HINFO, RFC 8482).
+#[inline(always)]
+pub fn answers(qtype: u16, rtype: u16) -> bool {
+    rtype == qtype
+        || qtype == TYPE_ANY
+        || rtype == TYPE_CNAME
+        || rtype == TYPE_DNAME
+        || rtype == TYPE_RRSIG
+}
+
+/// Parse the first question of the dns packet starting at `dns`.
+#[inline(always)]
+pub fn parse_question<P: Packet>(ctx: &P, dns: usize) -> Result<Question, ()> {
+    let end = skip_name(ctx, dns + DNS_HLEN)?;
+    // QTYPE(2) QCLASS(2)
+    let data: [u8; 4] = ctx.read(end)?;
+    Ok(Question {
+        qtype: u16::from_be_bytes([data[0], data[1]]),
+        end: end + 4,
+    })
+}
+
+/// Parse the resource record starting at `offset`.
+#[inline(always)]
+pub fn parse_answer<P: Packet>(ctx: &P, offset: usize) -> Result<Answer, ()> {
+    let name: [u8; 2] = ctx.read(offset)?;
+    let offset = skip_name(ctx, offset)?;
+    // TYPE(2) CLASS(2) TTL(4) RDLENGTH(2)
+    let data: [u8; 10] = ctx.read(offset)?;
+    Ok(Answer {
+        name: u16::from_be_bytes(name),
+        rtype: u16::from_be_bytes([data[0], data[1]]),
+        ttl: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+        rdlength: u16::from_be_bytes([data[8], data[9]]),
+        rdata: offset + 10,
+    })
+}
+
+/// Whether one of the `count` additional records starting at `offset` is an OPT record.
+#[inline(always)]
+pub fn has_opt<P: Packet>(ctx: &P, offset: usize, count: u16) -> Result<bool, ()> {
+    Ok(find_opt(ctx, offset, count)?.is_some())
+}
+
+/// Find the OPT record among the `count` additional records starting at `offset`.
+///
+/// Fails when it may be past the records walked, rather than claiming there is none.
+#[inline(always)]
+pub fn find_opt<P: Packet>(ctx: &P, mut offset: usize, count: u16) -> Result<Option<Answer>, ()> {
+    for i in 0..MAX_ADDITIONALS {
+        if i >= count as usize {
+            return Ok(None);
+        }
+        let record = parse_answer(ctx, offset)?;
+        if record.rtype == TYPE_OPT {
+            return Ok(Some(record));
+        }
+        offset = record.rdata + record.rdlength as usize;
+    }
+    if count as usize > MAX_ADDITIONALS {
+        return Err(());
+    }
+    Ok(None)
+}
+
+/// Find the OPT record of the dns packet starting at `dns`, walking all its
+/// sections. Fails on packets with more records than it walks.
+#[inline(always)]
+pub fn find_packet_opt<P: Packet>(ctx: &P, dns: usize) -> Result<Option<Answer>, ()> {
+    let header: [u8; DNS_HLEN] = ctx.read(dns)?;
+    let records = u16::from_be_bytes([header[6], header[7]]) as usize
+        + u16::from_be_bytes([header[8], header[9]]) as usize;
+    if records > MAX_RECORDS {
+        return Err(());
+    }
+    let mut offset = parse_question(ctx, dns)?.end;
+    for i in 0..MAX_RECORDS {
+        if i >= records {
+            break;
+        }
+        let record = parse_answer(ctx, offset)?;
+        offset = record.rdata + record.rdlength as usize;
+    }
+    find_opt(ctx, offset, u16::from_be_bytes([header[10], header[11]]))
+}
@@ -4,32 +4,113 @@
 
 #[allow(dead_code, non_camel_case_types, unused)]
 mod bindings;
+mod case;
+#[cfg(feature = "conntrack")]
+#[allow(non_camel_case_types)]
+mod conntrack;
 #[allow(dead_code, non_camel_case_types, unused)]
 mod constants;
+mod cookie;
+mod csum;
+mod dns;
+mod packet;
+mod truncate;
 
 use aya_bpf::{
-    bindings::xdp_action,
-    macros::{map, xdp},
-    maps::{HashMap, PerfEventArray},
-    programs::XdpContext,
+    bindings::{xdp_action, BPF_F_NO_PREALLOC, TC_ACT_OK},
+    helpers::bpf_ktime_get_ns,
+    macros::{classifier, map, xdp},
+    maps::{
+        lpm_trie::{Key, LpmTrie},
+        Array, HashMap, LruHashMap, PerCpuHashMap, PerfEventArray,
+    },
+    programs::{SkBuffContext, XdpContext},
 };
-use bindings::{ethhdr, iphdr, udphdr};
-use clean_dns_common::PacketLog;
-use constants::{ETH_HLEN, ETH_P_IP, IPPROTO_UDP};
+use bindings::{ethhdr, iphdr, tcphdr, udphdr};
+use clean_dns_common::{
+    Abi, CaptureConfig, EventRate, FlowInfo, FlowKey, IpTtlKey, PacketLog, QueryInfo, QueryKey,
+    RateKey, TokenBucket, UpstreamPolicy, COOKIE_LEN, EVENT_FLAG_ANSWER_NAME,
+    EVENT_FLAG_ANSWER_TYPE, EVENT_FLAG_EDNS_QUERY, EVENT_FLAG_MATCHED, EVENT_FLAG_OPT,
+    EVENT_FLAG_SINGLE_ANSWER, EVENT_FLAG_TCP_RST, EVENT_FLAG_TRUNCATED, MAX_CAPTURE, POLICY_0X20,
+    POLICY_ANSWER_NAME, POLICY_ANSWER_TTL, POLICY_ANSWER_TYPE, POLICY_AUTHORITATIVE,
+    POLICY_COOKIES, POLICY_DONT_FRAGMENT, POLICY_DROP_DUPLICATES, POLICY_IP_ID_ZERO, POLICY_IP_TTL,
+    POLICY_LEARN, POLICY_MIN_RTT, POLICY_REQUIRE_EDNS, POLICY_TCP_RST, POLICY_TRUNCATE,
+    POLICY_UNSOLICITED, QUERY_FLAG_0X20, QUERY_FLAG_ANSWERED, QUERY_FLAG_COOKIE, QUERY_FLAG_EDNS,
+    REASON_ANSWER_NAME, REASON_ANSWER_TTL, REASON_ANSWER_TYPE, REASON_AUTHORITATIVE,
+    REASON_BAD_COOKIE, REASON_BOGUS_ANSWER, REASON_CASE_MISMATCH, REASON_DONT_FRAGMENT,
+    REASON_DUPLICATE, REASON_IP_ID_ZERO, REASON_IP_TTL, REASON_MISSING_OPT, REASON_NONE,
+    REASON_QNAME_MISMATCH, REASON_RST_IP_ID, REASON_RST_IP_TTL, REASON_RST_WINDOW, REASON_TOO_FAST,
+    REASON_UNSOLICITED,
+};
+#[cfg(feature = "conntrack")]
+use clean_dns_common::{EVENT_FLAG_CONNTRACK, REASON_NO_CONNTRACK};
+use constants::{ETH_HLEN, ETH_P_IP, IPPROTO_TCP, IPPROTO_UDP};
 use core::mem;
 use memoffset::offset_of;
+use packet::Packet;
 
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo) -> ! {
     unsafe { core::hint::unreachable_unchecked() }
 }
 
+// read by userspace from the object file, the section name is ABI_SECTION
+#[no_mangle]
+#[used]
+#[link_section = "clean_dns_abi"]
+static ABI: Abi = Abi::CURRENT;
+
 #[map(name = "EVENTS")]
 static mut EVENTS: PerfEventArray<PacketLog> =
     PerfEventArray::<PacketLog>::with_max_entries(1024, 0);
 
+// token bucket limiting the events, filled by userspace
+#[map(name = "EVENT_RATE")]
+static mut EVENT_RATE: Array<EventRate> = Array::<EventRate>::with_max_entries(1, 0);
+
+// bytes of the frame captured with the events, set by userspace
+#[map(name = "CAPTURE")]
+static mut CAPTURE: Array<CaptureConfig> = Array::<CaptureConfig>::with_max_entries(1, 0);
+
+#[map(name = "EVENT_BUCKETS")]
+static mut EVENT_BUCKETS: LruHashMap<RateKey, TokenBucket> =
+    LruHashMap::<RateKey, TokenBucket>::with_max_entries(4096, 0);
+
 #[map(name = "BLOCKLIST")]
-static mut BLOCKLIST: HashMap<u32, u32> = HashMap::<u32, u32>::with_max_entries(1024, 0);
+static mut BLOCKLIST: HashMap<u32, UpstreamPolicy> =
+    HashMap::<u32, UpstreamPolicy>::with_max_entries(1024, 0);
+
+// queries sent to the BLOCKLIST upstreams, recorded on egress
+#[map(name = "QUERIES")]
+static mut QUERIES: LruHashMap<QueryKey, QueryInfo> =
+    LruHashMap::<QueryKey, QueryInfo>::with_max_entries(65536, 0);
+
+// client cookie of the upstreams with POLICY_COOKIES, filled by userspace
+#[map(name = "COOKIES")]
+static mut COOKIES: HashMap<u32, [u8; COOKIE_LEN]> =
+    HashMap::<u32, [u8; COOKIE_LEN]>::with_max_entries(1024, 0);
+
+// TCP connections to the BLOCKLIST upstreams on TCP_PORTS
+#[map(name = "TCP_FLOWS")]
+static mut TCP_FLOWS: LruHashMap<FlowKey, FlowInfo> =
+    LruHashMap::<FlowKey, FlowInfo>::with_max_entries(65536, 0);
+
+// upstream ports whose TCP RSTs are checked, filled by userspace
+#[map(name = "TCP_PORTS")]
+static mut TCP_PORTS: HashMap<u16, u32> = HashMap::<u16, u32>::with_max_entries(64, 0);
+
+#[map(name = "IP_TTL_STATS")]
+static mut IP_TTL_STATS: PerCpuHashMap<IpTtlKey, u64> =
+    PerCpuHashMap::<IpTtlKey, u64>::with_max_entries(16384, 0);
+
+// known-bogus answer addresses, keyed by the address in network byte order
+#[map(name = "BOGUS_V4")]
+static mut BOGUS_V4: LpmTrie<u32, u32> =
+    LpmTrie::<u32, u32>::with_max_entries(1024, BPF_F_NO_PREALLOC);
+
+#[map(name = "BOGUS_V6")]
+static mut BOGUS_V6: LpmTrie<[u8; 16], u32> =
+    LpmTrie::<[u8; 16], u32>::with_max_entries(1024, BPF_F_NO_PREALLOC);
 
 #[xdp(name = "clean_dns")]
 pub fn clean_dns(ctx: XdpContext) -> u32 {
@@ -50,61 +131,511 @@ fn try_clean_dns(ctx: XdpContext) -> Result<u32, ()> {
     let protocol = unsafe { (*ip).protocol };
     let source = u32::from_be(unsafe { (*ip).saddr });
     let destination = u32::from_be(unsafe { (*ip).daddr });
+    let ip_ttl = unsafe { (*ip).ttl } as u32;
 
     let mut log_entry = PacketLog {
+        header: PacketLog::HEADER,
         ipv4_src_addr: source,
         ipv4_dst_addr: destination,
         action: xdp_action::XDP_PASS,
+        reason: REASON_NONE,
+        answer_ttl: 0,
+        ip_ttl,
+        ip_id: u16::from_be(unsafe { (*ip).id }) as u32,
+        ip_frag_off: u16::from_be(unsafe { (*ip).frag_off }) as u32,
+        dns_flags: 0,
+        flags: 0,
+        rtt_us: 0,
+        suppressed: 0,
+        pkt_len: (ctx.data_end() - ctx.data()) as u32,
+        cap_len: 0,
+        _pad: 0,
+        ktime_ns: unsafe { bpf_ktime_get_ns() },
     };
-    // only match udp and BLOCKLIST
-    if protocol != IPPROTO_UDP as u8 || !block_ip(source) {
+    if protocol == IPPROTO_TCP as u8 {
+        let tcp = ETH_HLEN as usize + unsafe { (*ip).ihl() * 4 } as usize;
+        return try_clean_tcp(&ctx, tcp, log_entry);
+    }
+    // only match udp
+    if protocol != IPPROTO_UDP as u8 {
         return Ok(xdp_action::XDP_PASS);
     }
 
-    let udphdr: *const udphdr =
-        unsafe { ptr_at(&ctx, ETH_HLEN as usize + ((*ip).ihl() * 4) as usize)? };
-    let source = u16::from_be(unsafe { (*udphdr).source });
+    let udp = ETH_HLEN as usize + unsafe { (*ip).ihl() * 4 } as usize;
+    let udphdr: *const udphdr = unsafe { ptr_at(&ctx, udp)? };
     // only match 53
-    if source != 53 {
+    if u16::from_be(unsafe { (*udphdr).source }) != 53 {
         return Ok(xdp_action::XDP_PASS);
     }
-    let action = 'check: {
+    let dns = udp + mem::size_of::<udphdr>();
+    // get the 12 byte dns header(6,7 is Answer RRs, 8,9 is Authority RRs, 10,11 is Additional RRs)
+    let data: [u8; 12] = unsafe { *ptr_at(&ctx, dns)? };
+    log_entry.dns_flags = u16::from_be_bytes([data[2], data[3]]) as u32;
+
+    // drop single answer responses pointing to a bogus address, whatever the upstream is
+    if data[6] == 0 && data[7] == 1 && bogus_answer(&ctx, dns).unwrap_or(false) {
+        log_entry.action = xdp_action::XDP_DROP;
+        log_entry.reason = REASON_BOGUS_ANSWER;
+        output_event(&ctx, &mut log_entry);
+        return Ok(xdp_action::XDP_DROP);
+    }
+    // only match BLOCKLIST
+    let policy = match upstream_policy(source) {
+        Some(policy) => policy,
+        None => return Ok(xdp_action::XDP_PASS),
+    };
+
+    count_ip_ttl(source, ip_ttl);
+
+    let query_key = QueryKey {
+        client_addr: destination,
+        upstream_addr: source,
+        client_port: u16::from_be(unsafe { (*udphdr).dest }),
+        txid: u16::from_be_bytes([data[0], data[1]]),
+    };
+    let query = unsafe { QUERIES.get(&query_key).copied() };
+    if let Some(query) = query {
+        log_entry.flags |= EVENT_FLAG_MATCHED;
+        // a query from over an hour ago must not wrap around to a fast answer
+        let rtt_us = log_entry.ktime_ns.saturating_sub(query.timestamp) / 1000;
+        log_entry.rtt_us = rtt_us.min(u32::MAX as u64) as u32;
+        if query.flags & QUERY_FLAG_EDNS != 0 {
+            log_entry.flags |= EVENT_FLAG_EDNS_QUERY;
+        }
+    }
+
+    #[cfg(feature = "conntrack")]
+    let tracked = conntrack::udp_flow(
+        &ctx,
+        unsafe { (*ip).saddr },
+        unsafe { (*ip).daddr },
+        unsafe { (*udphdr).source },
+        unsafe { (*udphdr).dest },
+    );
+    #[cfg(feature = "conntrack")]
+    if tracked == Some(true) {
+        log_entry.flags |= EVENT_FLAG_CONNTRACK;
+    }
+
+    let (action, reason) = 'check: {
+        // only report single answer responses with what the checks look at
+        if policy.flags & POLICY_LEARN != 0 {
+            if data[6] == 0 && data[7] == 1 && data[8] == 0 && data[9] == 0 {
+                let question = dns::parse_question(&ctx, dns)?;
+                let answer = dns::parse_answer(&ctx, question.end)?;
+                log_entry.answer_ttl = answer.ttl;
+                log_entry.flags |= EVENT_FLAG_SINGLE_ANSWER;
+                if dns::answers(question.qtype, answer.rtype) {
+                    log_entry.flags |= EVENT_FLAG_ANSWER_TYPE;
+                }
+                if answer.name == dns::QUESTION_POINTER {
+                    log_entry.flags |= EVENT_FLAG_ANSWER_NAME;
+                }
+                let additionals = u16::from_be_bytes([data[10], data[11]]);
+                let additional = answer.rdata + answer.rdlength as usize;
+                if dns::has_opt(&ctx, additional, additionals) == Ok(true) {
+                    log_entry.flags |= EVENT_FLAG_OPT;
+                }
+            }
+            break 'check (xdp_action::XDP_PASS, REASON_NONE);
+        }
+        // drop if the kernel never sent anything the response could answer
+        #[cfg(feature = "conntrack")]
+        if tracked == Some(false) {
+            break 'check (xdp_action::XDP_DROP, REASON_NO_CONNTRACK);
+        }
+        // drop if we never asked the upstream this question, the queries
+        // whose question can't be hashed weren't recorded on egress
+        if policy.flags & POLICY_UNSOLICITED != 0 {
+            if let Ok(qname) = dns::hash_qname(&ctx, dns + dns::DNS_HLEN) {
+                match query {
+                    None => break 'check (xdp_action::XDP_DROP, REASON_UNSOLICITED),
+                    Some(query) if qname.hash != query.qname_hash => {
+                        break 'check (xdp_action::XDP_DROP, REASON_QNAME_MISMATCH)
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+        // drop if the query was already answered, the cache may already hold the legitimate answer
+        if policy.flags & POLICY_DROP_DUPLICATES != 0 {
+            if let Some(query) = query {
+                if query.flags & QUERY_FLAG_ANSWERED != 0 {
+                    break 'check (xdp_action::XDP_DROP, REASON_DUPLICATE);
+                }
+            }
+        }
+        // drop if the upstream didn't echo the randomized case, a blind injector can't guess it
+        if policy.flags & POLICY_0X20 != 0 {
+            if let Some(query) = query {
+                if query.flags & QUERY_FLAG_0X20 != 0
+                    && dns::hash_qname(&ctx, dns + dns::DNS_HLEN)?.case_hash
+                        != query.qname_case_hash
+                {
+                    break 'check (xdp_action::XDP_DROP, REASON_CASE_MISMATCH);
+                }
+            }
+        }
+        // drop if the upstream didn't echo our client cookie, only it knows the query carried one
+        if policy.flags & POLICY_COOKIES != 0 {
+            if let Some(query) = query {
+                // responses too large to parse aren't verified, they pass
+                if query.flags & QUERY_FLAG_COOKIE != 0
+                    && cookie::verify(&ctx, dns, &query.cookie) == Ok(false)
+                {
+                    break 'check (xdp_action::XDP_DROP, REASON_BAD_COOKIE);
+                }
+            }
+        }
+        // drop if the answer came back faster than the upstream is reachable, the injector is closer
+        if policy.flags & POLICY_MIN_RTT != 0
+            && query.is_some()
+            && log_entry.rtt_us < policy.min_rtt_us
+        {
+            break 'check (xdp_action::XDP_DROP, REASON_TOO_FAST);
+        }
+        // drop if the packet didn't travel as far as the upstream's ones
+        if policy.flags & POLICY_IP_TTL != 0
+            && (ip_ttl < policy.ip_ttl.min || ip_ttl > policy.ip_ttl.max)
+        {
+            break 'check (xdp_action::XDP_DROP, REASON_IP_TTL);
+        }
         // drop if id is 0
-        if u16::from_be(unsafe { (*ip).id }) == 0 {
-            break 'check xdp_action::XDP_DROP;
+        if policy.flags & POLICY_IP_ID_ZERO != 0 && log_entry.ip_id == 0 {
+            break 'check (xdp_action::XDP_DROP, REASON_IP_ID_ZERO);
         }
         // drop if flag is 0x40(Don't fragment)
-        if u16::from_be(unsafe { (*ip).frag_off }) == 0x0040 {
-            break 'check xdp_action::XDP_DROP;
-        }
-        // get first 10 byte udp data(7,8 is Answer RRs, 8,9 is Authority RRs)
-        let data: [u8; 10] = unsafe {
-            *ptr_at(
-                &ctx,
-                ETH_HLEN as usize + ((*ip).ihl() * 4) as usize + core::mem::size_of::<udphdr>(),
-            )?
-        };
+        if policy.flags & POLICY_DONT_FRAGMENT != 0 && log_entry.ip_frag_off == 0x0040 {
+            break 'check (xdp_action::XDP_DROP, REASON_DONT_FRAGMENT);
+        }
         // pass if the dns packet has multiple answers
         if data[6] != 0 || data[7] != 1 {
             // Answer RR != 1
-            break 'check xdp_action::XDP_PASS;
+            break 'check (xdp_action::XDP_PASS, REASON_NONE);
         }
         // pass if the dns packet has authority answer
         if data[8] != 0 || data[9] != 0 {
             // Authority RR != 0
-            break 'check xdp_action::XDP_PASS;
+            break 'check (xdp_action::XDP_PASS, REASON_NONE);
         }
         // drop if dns flag has Authoritative mark
-        if (data[2] & 0b0000_0100) != 0 {
-            break 'check xdp_action::XDP_DROP;
+        if policy.flags & POLICY_AUTHORITATIVE != 0 && (data[2] & 0b0000_0100) != 0 {
+            break 'check (xdp_action::XDP_DROP, REASON_AUTHORITATIVE);
         }
-        xdp_action::XDP_PASS
+        // upstreams only echo an OPT record to queries carrying one
+        let require_edns =
+            policy.flags & POLICY_REQUIRE_EDNS != 0 && log_entry.flags & EVENT_FLAG_EDNS_QUERY != 0;
+        // drop if the upstream always answers EDNS0 queries with EDNS0 but there is no OPT record
+        if require_edns && data[10] == 0 && data[11] == 0 {
+            break 'check (xdp_action::XDP_DROP, REASON_MISSING_OPT);
+        }
+        if require_edns
+            || policy.flags & (POLICY_ANSWER_TYPE | POLICY_ANSWER_NAME | POLICY_ANSWER_TTL) != 0
+        {
+            let question = dns::parse_question(&ctx, dns)?;
+            let answer = dns::parse_answer(&ctx, question.end)?;
+            log_entry.answer_ttl = answer.ttl;
+            // drop if the answer doesn't answer the question
+            if policy.flags & POLICY_ANSWER_TYPE != 0 && !dns::answers(question.qtype, answer.rtype)
+            {
+                break 'check (xdp_action::XDP_DROP, REASON_ANSWER_TYPE);
+            }
+            // drop if the answer name isn't compressed to the question(0xc00c)
+            if policy.flags & POLICY_ANSWER_NAME != 0 && answer.name != dns::QUESTION_POINTER {
+                break 'check (xdp_action::XDP_DROP, REASON_ANSWER_NAME);
+            }
+            // drop if the answer TTL is one the injector uses
+            if policy.flags & POLICY_ANSWER_TTL != 0 && policy.forged_ttl(answer.ttl) {
+                break 'check (xdp_action::XDP_DROP, REASON_ANSWER_TTL);
+            }
+            let additionals = u16::from_be_bytes([data[10], data[11]]);
+            let additional = answer.rdata + answer.rdlength as usize;
+            // an OPT record past the records walked isn't missing
+            if require_edns && dns::has_opt(&ctx, additional, additionals) == Ok(false) {
+                break 'check (xdp_action::XDP_DROP, REASON_MISSING_OPT);
+            }
+        }
+        (xdp_action::XDP_PASS, REASON_NONE)
     };
+    if let (xdp_action::XDP_PASS, Some(mut query)) = (action, query) {
+        // the first response passing the checks answers the query
+        if policy.flags & POLICY_DROP_DUPLICATES != 0 {
+            query.flags |= QUERY_FLAG_ANSWERED;
+            unsafe {
+                let _ = QUERIES.insert(&query_key, &query, 0);
+            }
+        }
+        // give the client back the case it asked with
+        if query.flags & QUERY_FLAG_0X20 != 0 {
+            let _ = case::restore(&ctx, udp, dns + dns::DNS_HLEN, query.case_mask);
+        }
+    }
     log_entry.action = action;
+    log_entry.reason = reason;
+    // hand the client a truncated reply rather than nothing, it retries over TCP right away
+    let truncate = if action == xdp_action::XDP_DROP && policy.flags & POLICY_TRUNCATE != 0 {
+        dns::parse_question(&ctx, dns).ok()
+    } else {
+        None
+    };
+    if truncate.is_some() {
+        log_entry.action = xdp_action::XDP_PASS;
+        log_entry.flags |= EVENT_FLAG_TRUNCATED;
+    }
+    // the event captures the response as received, not the reply it becomes
+    output_event(&ctx, &mut log_entry);
+    let question = match truncate {
+        Some(question) => question,
+        None => return Ok(action),
+    };
+    if let Some(query) = query {
+        if query.flags & QUERY_FLAG_0X20 != 0
+            && reason != REASON_CASE_MISMATCH
+            && reason != REASON_QNAME_MISMATCH
+        {
+            let _ = case::restore(&ctx, udp, dns + dns::DNS_HLEN, query.case_mask);
+        }
+    }
+    // everything rewritten lies before the end of the parsed question, the
+    // rewrite can't fail and the event already reported the reply
+    if truncate::rewrite(&ctx, udp, dns, question.end).is_err() {
+        return Ok(xdp_action::XDP_DROP);
+    }
+    Ok(xdp_action::XDP_PASS)
+}
+
+#[classifier(name = "clean_dns_egress")]
+pub fn clean_dns_egress(ctx: SkBuffContext) -> i32 {
+    let _ = try_clean_dns_egress(ctx);
+    TC_ACT_OK
+}
+
+// clients retry within seconds, a query reusing the port, id and name later on is a new one
+const RETRANSMIT_WINDOW_NS: u64 = 5_000_000_000;
+
+// record the queries sent to the BLOCKLIST upstreams
+#[inline(always)]
+fn try_clean_dns_egress(mut ctx: SkBuffContext) -> Result<(), ()> {
+    let h_proto = u16::from_be(ctx.read(offset_of!(ethhdr, h_proto))?);
+    // only match ip
+    if h_proto != ETH_P_IP as u16 {
+        return Ok(());
+    }
+    let ip: iphdr = ctx.read(ETH_HLEN as usize)?;
+    let destination = u32::from_be(ip.daddr);
+    // only match udp
+    if ip.protocol != IPPROTO_UDP as u8 {
+        return Ok(());
+    }
+    // only match BLOCKLIST
+    let policy = match upstream_policy(destination) {
+        Some(policy) => policy,
+        None => return Ok(()),
+    };
+    let udp = ETH_HLEN as usize + (ip.ihl() * 4) as usize;
+    let udphdr: udphdr = ctx.read(udp)?;
+    // only match 53
+    if u16::from_be(udphdr.dest) != 53 {
+        return Ok(());
+    }
+    let dns = udp + mem::size_of::<udphdr>();
+    let header: [u8; dns::DNS_HLEN] = ctx.read(dns)?;
+    // only match queries
+    if header[2] & 0b1000_0000 != 0 {
+        return Ok(());
+    }
+    // also makes sure the whole QNAME is within bounds before touching it
+    let mut qname = dns::hash_qname(&ctx, dns + dns::DNS_HLEN)?;
+    let mut flags = 0;
+    let additionals = u16::from_be_bytes([header[10], header[11]]);
+    if additionals != 0 {
+        let edns = dns::parse_question(&ctx, dns)
+            .and_then(|question| dns::has_opt(&ctx, question.end, additionals));
+        if edns == Ok(true) {
+            flags |= QUERY_FLAG_EDNS;
+        }
+    }
+    let key = QueryKey {
+        client_addr: u32::from_be(ip.saddr),
+        upstream_addr: destination,
+        client_port: u16::from_be(udphdr.source),
+        txid: u16::from_be_bytes([header[0], header[1]]),
+    };
+    // a retransmission reuses the port and id, the upstream may still answer
+    // the first transmission. An answered or old entry is a new query that
+    // happens to reuse them.
+    let now = unsafe { bpf_ktime_get_ns() };
+    let sent = unsafe { QUERIES.get(&key).copied() }.filter(|sent| {
+        sent.qname_hash == qname.hash
+            && sent.flags & QUERY_FLAG_ANSWERED == 0
+            && now.saturating_sub(sent.timestamp) < RETRANSMIT_WINDOW_NS
+    });
+    // the rewrites are optional, a query they fail on is still recorded
+    let mut case_mask = 0;
+    if policy.flags & POLICY_0X20 != 0 {
+        let rewritten = match sent {
+            // ask with the same case again, or the late answer fails the check
+            Some(sent) if sent.flags & QUERY_FLAG_0X20 != 0 => {
+                case::reapply(&mut ctx, udp, dns + dns::DNS_HLEN, sent.case_mask)
+            }
+            _ => case::randomize(&mut ctx, udp, dns + dns::DNS_HLEN),
+        };
+        if let Ok(mask) = rewritten {
+            if let Ok(randomized) = dns::hash_qname(&ctx, dns + dns::DNS_HLEN) {
+                qname = randomized;
+                case_mask = mask;
+                flags |= QUERY_FLAG_0X20;
+            }
+        }
+    }
+    let mut cookie = [0; COOKIE_LEN];
+    if policy.flags & POLICY_COOKIES != 0 {
+        if let Some(client) = unsafe { COOKIES.get(&destination).copied() } {
+            if cookie::add(&mut ctx, udp, dns, &client) == Ok(true) {
+                flags |= QUERY_FLAG_COOKIE;
+                cookie = client;
+            }
+        }
+    }
+    let info = QueryInfo {
+        qname_hash: qname.hash,
+        qname_case_hash: qname.case_hash,
+        // the RTT of a late answer to the first transmission isn't shortened
+        timestamp: match sent {
+            Some(sent) => sent.timestamp,
+            None => now,
+        },
+        case_mask,
+        flags,
+        cookie,
+    };
+    unsafe {
+        let _ = QUERIES.insert(&key, &info, 0);
+    }
+    Ok(())
+}
+
+// the IP ids of a RST sent by the upstream follow the ones of the flow
+const RST_IP_ID_SLACK: i16 = 1024;
+
+// learn the flows to the BLOCKLIST upstreams and check their RSTs against them
+#[inline(always)]
+fn try_clean_tcp(ctx: &XdpContext, tcp: usize, mut log_entry: PacketLog) -> Result<u32, ()> {
+    let tcphdr: tcphdr = ctx.read(tcp)?;
+    let upstream_port = u16::from_be(tcphdr.source);
+    // only match TCP_PORTS
+    if unsafe { TCP_PORTS.get(&upstream_port).is_none() } {
+        return Ok(xdp_action::XDP_PASS);
+    }
+    // only match BLOCKLIST
+    match upstream_policy(log_entry.ipv4_src_addr) {
+        Some(policy) if policy.flags & POLICY_TCP_RST != 0 => {}
+        _ => return Ok(xdp_action::XDP_PASS),
+    }
+    let key = FlowKey {
+        client_addr: log_entry.ipv4_dst_addr,
+        upstream_addr: log_entry.ipv4_src_addr,
+        client_port: u16::from_be(tcphdr.dest),
+        upstream_port,
+    };
+    let window = u16::from_be(tcphdr.window) as u32;
+    let flow = unsafe { TCP_FLOWS.get(&key).copied() };
+    if tcphdr.rst() == 0 {
+        // the IP TTL is the flow's first one, a forged segment with another
+        // one doesn't get to prime the fingerprint for its RST
+        if matches!(flow, Some(flow) if flow.ip_ttl != log_entry.ip_ttl) {
+            return Ok(xdp_action::XDP_PASS);
+        }
+        let info = FlowInfo {
+            ip_ttl: log_entry.ip_ttl,
+            ip_id: log_entry.ip_id,
+            window,
+        };
+        unsafe {
+            let _ = TCP_FLOWS.insert(&key, &info, 0);
+        }
+        return Ok(xdp_action::XDP_PASS);
+    }
+    // nothing to compare a RST of an unknown flow with
+    let flow = match flow {
+        Some(flow) => flow,
+        None => return Ok(xdp_action::XDP_PASS),
+    };
+    log_entry.flags |= EVENT_FLAG_TCP_RST;
+    let ip_id_distance = (log_entry.ip_id as u16).wrapping_sub(flow.ip_id as u16) as i16;
+    let reason = if log_entry.ip_ttl != flow.ip_ttl {
+        REASON_RST_IP_TTL
+    } else if (flow.ip_id == 0) != (log_entry.ip_id == 0)
+        || ip_id_distance > RST_IP_ID_SLACK
+        || ip_id_distance < -RST_IP_ID_SLACK
+    {
+        REASON_RST_IP_ID
+    } else if window != 0 && window != flow.window {
+        REASON_RST_WINDOW
+    } else {
+        REASON_NONE
+    };
+    if reason == REASON_NONE {
+        // the flow is over
+        unsafe {
+            let _ = TCP_FLOWS.remove(&key);
+        }
+    } else {
+        log_entry.action = xdp_action::XDP_DROP;
+        log_entry.reason = reason;
+    }
+    output_event(ctx, &mut log_entry);
+    Ok(log_entry.action)
+}
+
+// output the event unless its upstream and reason are over the event rate
+#[inline(always)]
+fn output_event(ctx: &XdpContext, log_entry: &mut PacketLog) {
+    // the captured frame follows the event
+    if let Some(capture) = unsafe { CAPTURE.get(0) } {
+        let capture = if log_entry.reason != REASON_NONE {
+            capture.verdict_len
+        } else {
+            capture.pass_len
+        };
+        // a truncated reply is shorter than the frame it was rewritten from
+        let len = (ctx.data_end() - ctx.data()) as u32;
+        log_entry.cap_len = capture.min(MAX_CAPTURE).min(len);
+    }
+    let rate = match unsafe { EVENT_RATE.get(0) } {
+        Some(rate) if rate.cost_ns != 0 => *rate,
+        _ => {
+            unsafe { EVENTS.output(ctx, log_entry, log_entry.cap_len) };
+            return;
+        }
+    };
+    let key = RateKey {
+        upstream: log_entry.ipv4_src_addr,
+        reason: log_entry.reason,
+    };
+    let now = unsafe { bpf_ktime_get_ns() };
+    let mut bucket = unsafe { EVENT_BUCKETS.get(&key).copied() }.unwrap_or(TokenBucket {
+        credit_ns: rate.burst_ns,
+        refilled_ns: now,
+        suppressed: 0,
+        _pad: 0,
+    });
+    bucket.credit_ns += now.saturating_sub(bucket.refilled_ns);
+    if bucket.credit_ns > rate.burst_ns {
+        bucket.credit_ns = rate.burst_ns;
+    }
+    bucket.refilled_ns = now;
+    if bucket.credit_ns >= rate.cost_ns {
+        bucket.credit_ns -= rate.cost_ns;
+        log_entry.suppressed = bucket.suppressed;
+        bucket.suppressed = 0;
+        unsafe { EVENTS.output(ctx, log_entry, log_entry.cap_len) };
+    } else {
+        bucket.suppressed += 1;
+    }
     unsafe {
-        EVENTS.output(&ctx, &log_entry, 0);
+        let _ = EVENT_BUCKETS.insert(&key, &bucket, 0);
     }
-    return Ok(action);
 }
 
 #[inline(always)]
@@ -121,6 +652,35 @@ unsafe fn ptr_at<T>(ctx: &XdpContext, offset: usize) -> Result<*const T, ()> {
 }
 
 #[inline(always)]
-fn block_ip(address: u32) -> bool {
-    unsafe { BLOCKLIST.get(&address).is_some() }
+fn count_ip_ttl(upstream: u32, ttl: u32) {
+    let key = IpTtlKey { upstream, ttl };
+    unsafe {
+        let count = IP_TTL_STATS.get(&key).copied().unwrap_or(0);
+        let _ = IP_TTL_STATS.insert(&key, &(count + 1), 0);
+    }
+}
+
+#[inline(always)]
+fn upstream_policy(address: u32) -> Option<UpstreamPolicy> {
+    unsafe { BLOCKLIST.get(&address).copied() }
+}
+
+// check whether the single answer's A/AAAA RDATA is a known-bogus address
+#[inline(always)]
+fn bogus_answer(ctx: &XdpContext, dns: usize) -> Result<bool, ()> {
+    let question = dns::parse_question(ctx, dns)?;
+    let answer = dns::parse_answer(ctx, question.end)?;
+    match (answer.rtype, answer.rdlength) {
+        (dns::TYPE_A, 4) => {
+            let rdata: [u8; 4] = unsafe { *ptr_at(ctx, answer.rdata)? };
+            let key = Key::new(32, u32::from_ne_bytes(rdata));
+            Ok(unsafe { BOGUS_V4.get(&key).is_some() })
+        }
+        (dns::TYPE_AAAA, 16) => {
+            let rdata: [u8; 16] = unsafe { *ptr_at(ctx, answer.rdata)? };
+            let key = Key::new(128, rdata);
+            Ok(unsafe { BOGUS_V6.get(&key).is_some() })
+        }
+        _ => Ok(false),
+    }
 }
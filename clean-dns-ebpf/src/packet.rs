@@ -0,0 +1,22 @@
+use aya_bpf::programs::{SkBuffContext, XdpContext};
+
+use crate::ptr_at;
+
+/// Bounds checked packet access shared by the XDP and TC programs.
+pub trait Packet {
+    fn read<T: Copy>(&self, offset: usize) -> Result<T, ()>;
+}
+
+impl Packet for XdpContext {
+    #[inline(always)]
+    fn read<T: Copy>(&self, offset: usize) -> Result<T, ()> {
+        Ok(unsafe { ptr_at::<T>(self, offset)?.read_unaligned() })
+    }
+}
+
+impl Packet for SkBuffContext {
+    #[inline(always)]
+    fn read<T: Copy>(&self, offset: usize) -> Result<T, ()> {
+        self.load(offset).map_err(|_| ())
+    }
+}
@@ -0,0 +1,36 @@
+use aya_bpf::{helpers::bpf_xdp_adjust_tail, programs::XdpContext};
+
+use crate::{constants::ETH_HLEN, csum, dns, ptr_at};
+
+/// Rewrite the response whose dns packet starts at `dns` into an empty
+/// truncated reply to its question ending at `end`, so the client retries
+/// over TCP.
+#[inline(always)]
+pub fn rewrite(ctx: &XdpContext, udp: usize, dns: usize, end: usize) -> Result<(), ()> {
+    let header = unsafe { ptr_at::<[u8; dns::DNS_HLEN]>(ctx, dns)? as *mut [u8; dns::DNS_HLEN] };
+    unsafe {
+        // QR and TC, no AA, RCODE 0 and only the question left
+        (*header)[2] = ((*header)[2] | 0b1000_0010) & !0b0000_0100;
+        (*header)[3] &= 0xf0;
+        (*header)[6..].copy_from_slice(&[0; 6]);
+    }
+    let ip = ETH_HLEN as usize;
+    let tot_len = unsafe { ptr_at::<[u8; 2]>(ctx, ip + 2)? as *mut [u8; 2] };
+    let check = unsafe { ptr_at::<[u8; 2]>(ctx, ip + 10)? as *mut [u8; 2] };
+    let udp_len = unsafe { ptr_at::<[u8; 2]>(ctx, udp + 4)? as *mut [u8; 2] };
+    let udp_check = unsafe { ptr_at::<[u8; 2]>(ctx, udp + 6)? as *mut [u8; 2] };
+    unsafe {
+        let old = u16::from_be_bytes(*tot_len);
+        let new = (end - ip) as u16;
+        *tot_len = new.to_be_bytes();
+        *check = csum::replace2(u16::from_be_bytes(*check), old, new).to_be_bytes();
+        *udp_len = ((end - udp) as u16).to_be_bytes();
+        // the checksum is optional over IPv4, cheaper than summing what is left
+        *udp_check = [0; 2];
+    }
+    // the stack trims the frame to the IP total length anyway, a driver
+    // failing to shrink it only leaves the padding to be dropped later
+    let delta = ctx.data_end() - ctx.data() - end;
+    let _ = unsafe { bpf_xdp_adjust_tail(ctx.ctx, -(delta as i32)) };
+    Ok(())
+}
@@ -0,0 +1,128 @@
+use anyhow::{anyhow, bail};
+use clean_dns_common::{Abi, EventHeader, ABI_MIN_VERSION, ABI_SECTION};
+use object::{Object, ObjectSection};
+use std::mem;
+
+/// Make sure the eBPF `object` was built against the same shared types as us,
+/// a mismatch would silently garble the events and policies. An object from
+/// before fields were appended to the events is accepted.
+pub fn check(object: &[u8]) -> Result<(), anyhow::Error> {
+    let file = object::File::parse(object)?;
+    let section = file
+        .section_by_name(ABI_SECTION)
+        .ok_or_else(|| anyhow!("no {} section, the eBPF object is too old", ABI_SECTION))?;
+    let data = section.data()?;
+    let field = |i: usize| -> Result<u32, anyhow::Error> {
+        let bytes = data
+            .get(i * 4..i * 4 + 4)
+            .ok_or_else(|| anyhow!("truncated {} section", ABI_SECTION))?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    };
+    let abi = Abi {
+        version: field(0)?,
+        packet_log_size: field(1)?,
+        upstream_policy_size: field(2)?,
+    };
+    // events only ever grow, the fields an older object lacks are zero-filled
+    let current = Abi::CURRENT;
+    if abi.version < ABI_MIN_VERSION
+        || abi.version > current.version
+        || abi.upstream_policy_size != current.upstream_policy_size
+        || abi.packet_log_size > current.packet_log_size
+        || (abi.packet_log_size as usize) < mem::size_of::<EventHeader>()
+    {
+        bail!(
+            "the eBPF object was built for {:?}, this clean-dns expects {:?}",
+            abi,
+            current
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clean_dns_common::{PacketLog, ABI_VERSION};
+
+    // a relocatable ELF object holding only `section` named `name`
+    fn object(name: &str, section: &[u8]) -> Vec<u8> {
+        let names = format!("\0{}\0.shstrtab\0", name);
+        let mut elf = vec![0x7f, b'E', b'L', b'F', 2, 1, 1];
+        elf.resize(16, 0);
+        elf.extend_from_slice(&1u16.to_le_bytes()); // ET_REL
+        elf.extend_from_slice(&247u16.to_le_bytes()); // EM_BPF
+        elf.extend_from_slice(&1u32.to_le_bytes());
+        elf.extend_from_slice(&[0; 16]); // entry, program headers
+        let sections = 64 + section.len() + names.len();
+        let sections = sections + (8 - sections % 8) % 8;
+        elf.extend_from_slice(&(sections as u64).to_le_bytes());
+        elf.extend_from_slice(&0u32.to_le_bytes());
+        for half in [64u16, 0, 0, 64, 3, 2] {
+            elf.extend_from_slice(&half.to_le_bytes());
+        }
+        elf.extend_from_slice(section);
+        elf.extend_from_slice(names.as_bytes());
+        elf.resize(sections, 0);
+        let mut header = |name: u32, kind: u32, offset: usize, size: usize| {
+            elf.extend_from_slice(&name.to_le_bytes());
+            elf.extend_from_slice(&kind.to_le_bytes());
+            elf.extend_from_slice(&[0; 16]); // flags, address
+            elf.extend_from_slice(&(offset as u64).to_le_bytes());
+            elf.extend_from_slice(&(size as u64).to_le_bytes());
+            elf.extend_from_slice(&[0; 8]); // link, info
+            elf.extend_from_slice(&1u64.to_le_bytes());
+            elf.extend_from_slice(&0u64.to_le_bytes());
+        };
+        header(0, 0, 0, 0);
+        header(1, 1, 64, section.len()); // SHT_PROGBITS
+        let shstrtab = 64 + section.len();
+        header(name.len() as u32 + 2, 3, shstrtab, names.len()); // SHT_STRTAB
+        elf
+    }
+
+    fn abi(version: u32, packet_log_size: usize, upstream_policy_size: u32) -> Vec<u8> {
+        let abi = [version, packet_log_size as u32, upstream_policy_size];
+        let section = abi
+            .iter()
+            .flat_map(|field| field.to_le_bytes())
+            .collect::<Vec<_>>();
+        object(ABI_SECTION, &section)
+    }
+
+    #[test]
+    fn current() {
+        let current = Abi::CURRENT;
+        let policy = current.upstream_policy_size;
+        check(&abi(ABI_VERSION, mem::size_of::<PacketLog>(), policy)).unwrap();
+        // the objects of another clean-dns
+        assert!(check(&abi(ABI_VERSION + 1, mem::size_of::<PacketLog>(), policy)).is_err());
+        assert!(check(&abi(
+            ABI_MIN_VERSION - 1,
+            mem::size_of::<PacketLog>(),
+            policy
+        ))
+        .is_err());
+        assert!(check(&abi(ABI_VERSION, mem::size_of::<PacketLog>(), policy + 4)).is_err());
+    }
+
+    #[test]
+    fn older() {
+        let policy = Abi::CURRENT.upstream_policy_size;
+        // from before `ktime_ns` and its padding were appended
+        let before_ktime = mem::size_of::<PacketLog>() - 12;
+        check(&abi(ABI_MIN_VERSION, before_ktime, policy)).unwrap();
+        check(&abi(ABI_VERSION, before_ktime, policy)).unwrap();
+        check(&abi(ABI_VERSION, mem::size_of::<EventHeader>(), policy)).unwrap();
+        // events with fields this clean-dns doesn't know, or without a header
+        assert!(check(&abi(ABI_VERSION, mem::size_of::<PacketLog>() + 4, policy)).is_err());
+        assert!(check(&abi(ABI_VERSION, mem::size_of::<EventHeader>() - 1, policy)).is_err());
+    }
+
+    #[test]
+    fn missing() {
+        assert!(check(b"not an object").is_err());
+        assert!(check(&object(".text", &[0; 12])).is_err());
+        assert!(check(&object(ABI_SECTION, &[0; 8])).is_err());
+    }
+}
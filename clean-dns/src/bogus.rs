@@ -0,0 +1,107 @@
+use anyhow::{anyhow, Context as _};
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+};
+
+/// A bogus answer address or prefix.
+#[derive(Debug, Clone, Copy)]
+pub enum BogusNet {
+    V4(Ipv4Addr, u32),
+    V6(Ipv6Addr, u32),
+}
+
+impl std::str::FromStr for BogusNet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .with_context(|| format!("invalid address `{}`", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u32>()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| anyhow!("invalid prefix length `{}`", s))?,
+            None => max,
+        };
+        Ok(match addr {
+            IpAddr::V4(addr) => BogusNet::V4(addr, prefix),
+            IpAddr::V6(addr) => BogusNet::V6(addr, prefix),
+        })
+    }
+}
+
+/// Parse a bogus answer list.
+///
+/// Both dnsmasq configuration (`bogus-nxdomain=<addr>[/prefix]`, other options
+/// are ignored) and plain lists with one address or prefix per line are accepted.
+pub fn parse(content: &str) -> Result<Vec<BogusNet>, anyhow::Error> {
+    let mut nets = Vec::new();
+    for (n, line) in content.lines().enumerate() {
+        let line = match line.split_once('#') {
+            Some((line, _)) => line,
+            None => line,
+        }
+        .trim();
+        if line.is_empty() {
+            continue;
+        }
+        let value = match line.split_once('=').map(|(key, value)| (key.trim(), value)) {
+            Some(("bogus-nxdomain", value)) => value.trim(),
+            // other dnsmasq options
+            Some(_) => continue,
+            None => line,
+        };
+        nets.push(value.parse().with_context(|| format!("line {}", n + 1))?);
+    }
+    Ok(nets)
+}
+
+pub fn load(path: &Path) -> Result<Vec<BogusNet>, anyhow::Error> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    parse(&content).with_context(|| format!("failed to parse {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_dnsmasq() {
+        let nets = parse(
+            "# bogus answers\n\
+             bogus-nxdomain=1.2.3.4\n\
+             bogus-nxdomain = 10.0.0.0/8 # a whole network\n\
+             server=8.8.8.8\n\
+             \n\
+             2001:db8::/32\n",
+        )
+        .unwrap();
+        assert_eq!(nets.len(), 3);
+        assert!(matches!(nets[0], BogusNet::V4(addr, 32) if addr == Ipv4Addr::new(1, 2, 3, 4)));
+        assert!(matches!(nets[1], BogusNet::V4(addr, 8) if addr == Ipv4Addr::new(10, 0, 0, 0)));
+        assert!(
+            matches!(nets[2], BogusNet::V6(addr, 32) if addr == "2001:db8::".parse::<Ipv6Addr>().unwrap())
+        );
+    }
+
+    #[test]
+    fn parse_bad_prefix() {
+        assert!("1.2.3.4/33".parse::<BogusNet>().is_err());
+        assert!("2001:db8::/129".parse::<BogusNet>().is_err());
+        assert!("1.2.3.4/".parse::<BogusNet>().is_err());
+        assert!("1.2.3.4/x".parse::<BogusNet>().is_err());
+        assert!("1.2.3.4/0".parse::<BogusNet>().is_ok());
+        let error = parse("1.2.3.4\nbogus-nxdomain=1.2.3.4/40\n").unwrap_err();
+        assert!(format!("{:#}", error).contains("line 2"));
+    }
+}
@@ -0,0 +1,113 @@
+use anyhow::{bail, Context as _};
+use std::{
+    fs, io,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    task,
+};
+
+/// Answers a request read from the control socket, a single line of text.
+pub type Handler = Arc<dyn Fn(&str) -> Result<String, anyhow::Error> + Send + Sync>;
+
+/// Serve requests on the unix socket at `path` until the task is dropped.
+pub fn serve(path: PathBuf, handler: Handler) -> Result<(), anyhow::Error> {
+    match fs::symlink_metadata(&path) {
+        // a previous run may have left its socket behind, a running one answers
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+                bail!("clean-dns is already listening on {}", path.display());
+            }
+            fs::remove_file(&path)
+                .with_context(|| format!("failed to remove stale {}", path.display()))?;
+        }
+        Ok(_) => bail!("{} exists and isn't a socket", path.display()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("failed to stat {}", path.display())),
+    }
+    let listener = UnixListener::bind(&path)
+        .with_context(|| format!("failed to bind control socket {}", path.display()))?;
+    task::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    eprintln!("control socket: {}", e);
+                    continue;
+                }
+            };
+            let handler = handler.clone();
+            task::spawn(async move {
+                if let Err(e) = handle(stream, handler).await {
+                    eprintln!("control socket: {:#}", e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+async fn handle(stream: UnixStream, handler: Handler) -> Result<(), anyhow::Error> {
+    let mut stream = BufReader::new(stream);
+    let mut request = String::new();
+    stream.read_line(&mut request).await?;
+    let response = match handler(request.trim()) {
+        Ok(response) => response,
+        Err(e) => format!("error: {:#}\n", e),
+    };
+    stream.get_mut().write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+/// Send `request` to the daemon listening at `path` and return its response.
+pub async fn request(path: &Path, request: &str) -> Result<String, anyhow::Error> {
+    let mut stream = UnixStream::connect(path).await.with_context(|| {
+        format!(
+            "failed to connect to {}, is clean-dns running?",
+            path.display()
+        )
+    })?;
+    stream
+        .write_all(format!("{}\n", request).as_bytes())
+        .await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handler() -> Handler {
+        Arc::new(|request: &str| Ok(format!("{}\n", request)))
+    }
+
+    #[tokio::test]
+    async fn takeover() {
+        let dir = std::env::temp_dir().join(format!("clean-dns-control-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("control.sock");
+
+        // a mistyped path is left alone
+        fs::write(&path, "data").unwrap();
+        assert!(serve(path.clone(), handler()).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "data");
+        fs::remove_file(&path).unwrap();
+
+        // the socket of a run that didn't clean up is replaced
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        serve(path.clone(), handler()).unwrap();
+        assert_eq!(request(&path, "ping").await.unwrap(), "ping\n");
+
+        // the running daemon keeps its socket
+        assert!(serve(path.clone(), handler()).is_err());
+        assert_eq!(request(&path, "ping").await.unwrap(), "ping\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
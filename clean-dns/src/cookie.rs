@@ -0,0 +1,67 @@
+use anyhow::Context as _;
+use clean_dns_common::COOKIE_LEN;
+use siphasher::sip::SipHasher24;
+use std::{
+    fs::{self, File, OpenOptions},
+    hash::Hasher,
+    io::{Read, Write},
+    net::Ipv4Addr,
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+};
+
+pub const SECRET_LEN: usize = 16;
+
+/// The secret the client cookies are derived from.
+#[derive(Clone, Copy)]
+pub struct Secret([u8; SECRET_LEN]);
+
+impl Secret {
+    pub fn random() -> Result<Self, anyhow::Error> {
+        let mut secret = [0; SECRET_LEN];
+        File::open("/dev/urandom")
+            .and_then(|mut urandom| urandom.read_exact(&mut secret))
+            .context("failed to read a random cookie secret")?;
+        Ok(Secret(secret))
+    }
+
+    /// Read the secret stored at `path`, creating it on first use.
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        match fs::read(path) {
+            Ok(content) => {
+                let secret = content.as_slice().try_into().map_err(|_| {
+                    anyhow::anyhow!(
+                        "{}: a cookie secret is {} bytes",
+                        path.display(),
+                        SECRET_LEN
+                    )
+                })?;
+                Ok(Secret(secret))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let secret = Self::random()?;
+                secret.save(path)?;
+                Ok(secret)
+            }
+            Err(e) => Err(e).with_context(|| format!("failed to read {}", path.display())),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), anyhow::Error> {
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)
+            .and_then(|mut file| file.write_all(&self.0))
+            .with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    /// The client cookie sent to `upstream` (RFC 7873 section 4.1).
+    pub fn client_cookie(&self, upstream: Ipv4Addr) -> [u8; COOKIE_LEN] {
+        let mut hasher = SipHasher24::new_with_key(&self.0);
+        hasher.write(&upstream.octets());
+        hasher.finish().to_be_bytes()
+    }
+}
@@ -0,0 +1,107 @@
+use anyhow::Context as _;
+use aya::programs::ProgramError;
+use std::{fmt::Write as _, fs, path::Path};
+
+use crate::probe::Features;
+
+/// The source line annotating each instruction of a verifier log.
+///
+/// With BTF line info the verifier prints `; <source> @ <file>:<line>` before
+/// the instructions generated from that line.
+pub fn source_map(log: &str) -> Vec<(usize, String)> {
+    let mut map = Vec::new();
+    let mut source = None;
+    for line in log.lines() {
+        if let Some(annotation) = line.strip_prefix("; ") {
+            source = Some(annotation.trim().to_string());
+            continue;
+        }
+        let insn = match line.split_once(':').map(|(insn, _)| insn.parse::<usize>()) {
+            Some(Ok(insn)) => insn,
+            _ => continue,
+        };
+        if let Some(source) = &source {
+            // the log walks the same instructions once per explored path
+            if !map.iter().any(|(seen, _)| *seen == insn) {
+                map.push((insn, source.clone()));
+            }
+        }
+    }
+    map
+}
+
+/// Write what is needed to understand why `program` was rejected to `path`.
+pub fn write_bundle(
+    path: &Path,
+    features: &Features,
+    program: &str,
+    error: &ProgramError,
+) -> Result<(), anyhow::Error> {
+    let mut bundle = String::new();
+    writeln!(bundle, "program: {}", program)?;
+    writeln!(bundle, "error: {}", error)?;
+    bundle.push_str(&features.report());
+    if let ProgramError::LoadError { verifier_log, .. } = error {
+        let map = source_map(verifier_log);
+        // the verifier stops at the instruction it rejected, the last one logged
+        let last = verifier_log
+            .lines()
+            .rev()
+            .find_map(|line| line.split_once(':')?.0.parse::<usize>().ok());
+        if let Some(insn) = last {
+            match map.iter().find(|(seen, _)| *seen == insn) {
+                Some((_, source)) => writeln!(bundle, "rejected at insn {}: {}", insn, source)?,
+                None => writeln!(bundle, "rejected at insn {}", insn)?,
+            }
+        }
+        writeln!(bundle, "\ninstructions:")?;
+        for (insn, source) in &map {
+            writeln!(bundle, "{:>6}: {}", insn, source)?;
+        }
+        writeln!(bundle, "\nverifier log:\n{}", verifier_log)?;
+    }
+    fs::write(path, bundle).with_context(|| format!("failed to write {}", path.display()))
+}
+
+/// Load error of `program`, writing the diagnostic bundle to `path` if given.
+pub fn load_error(
+    path: Option<&Path>,
+    features: &Features,
+    program: &str,
+    error: ProgramError,
+) -> anyhow::Error {
+    if let Some(path) = path {
+        match write_bundle(path, features, program, &error) {
+            Ok(()) => eprintln!("verifier diagnostics written to {}", path.display()),
+            Err(e) => eprintln!("{:#}", e),
+        }
+    }
+    anyhow::Error::from(error).context(format!("failed to load {}", program))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn source_lines() {
+        let log = "func#0 @0\n\
+                   ; let data = ctx.data(); @ main.rs:10\n\
+                   0: (61) r2 = *(u32 *)(r1 +0)\n\
+                   1: (61) r3 = *(u32 *)(r1 +4)\n\
+                   ; return Ok(XDP_PASS); @ main.rs:11\n\
+                   2: (b7) r0 = 2\n\
+                   from 2 to 3: R0=inv2\n\
+                   3: (95) exit\n\
+                   ; let data = ctx.data(); @ main.rs:10\n\
+                   0: (61) r2 = *(u32 *)(r1 +0)\n";
+        let data = "let data = ctx.data(); @ main.rs:10".to_string();
+        let exit = "return Ok(XDP_PASS); @ main.rs:11".to_string();
+        assert_eq!(
+            source_map(log),
+            [(0, data.clone()), (1, data), (2, exit.clone()), (3, exit)]
+        );
+        // without BTF line info
+        assert!(source_map("0: (b7) r0 = 2\n1: (95) exit\n").is_empty());
+    }
+}
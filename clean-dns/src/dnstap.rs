@@ -0,0 +1,425 @@
+use anyhow::{bail, Context as _};
+use clean_dns_common::{reason_str, EVENT_FLAG_TCP_RST};
+use std::{
+    fs::File,
+    io::{BufWriter, Read, Write},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, SyncSender, TrySendError},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+use crate::{events::Event, pipeline::Sink};
+
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+// frame streams control frames and fields
+const CONTROL_ACCEPT: u32 = 0x01;
+const CONTROL_START: u32 = 0x02;
+const CONTROL_STOP: u32 = 0x03;
+const CONTROL_READY: u32 = 0x04;
+const CONTROL_FINISH: u32 = 0x05;
+const CONTROL_FIELD_CONTENT_TYPE: u32 = 0x01;
+// longest control frame a receiver has to accept
+const MAX_CONTROL_LEN: usize = 512;
+
+// dnstap.proto
+const DNSTAP_TYPE_MESSAGE: u64 = 1;
+const MESSAGE_TYPE_FORWARDER_RESPONSE: u64 = 8;
+const SOCKET_FAMILY_INET: u64 = 1;
+const SOCKET_PROTOCOL_UDP: u64 = 1;
+
+// frames waiting for the writer, a slow collector loses the ones past it
+// instead of stalling the pipeline
+const QUEUE_LEN: usize = 4096;
+// a collector that went away is reconnected to at this pace
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+// how long a collector gets to acknowledge the end of the stream
+const FINISH_TIMEOUT: Duration = Duration::from_secs(1);
+// a collector not reading or answering for this long is reconnected to
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Writes the captured dns responses as dnstap frames, the verdict in the
+/// `extra` field. Responses longer than the capture are left out, they
+/// wouldn't parse.
+///
+/// The frames are written by a thread of their own, so neither a slow disk
+/// nor a slow collector holds up the other sinks.
+pub struct DnstapSink {
+    frames: Option<SyncSender<Vec<u8>>>,
+    writer: Option<JoinHandle<()>>,
+    dropped: u64,
+    /// responses longer than the capture
+    cut: u64,
+}
+
+impl DnstapSink {
+    /// Write a frame streams file at `path`.
+    pub fn create(path: &Path) -> Result<Self, anyhow::Error> {
+        let file =
+            File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+        let mut out = BufWriter::new(file);
+        write_control(&mut out, CONTROL_START, true)?;
+        out.flush()?;
+        Ok(Self::spawn(Output::File(out)))
+    }
+
+    /// Stream to the collector listening on the unix socket at `path`,
+    /// reconnecting when it goes away.
+    pub fn connect(path: &Path) -> Result<Self, anyhow::Error> {
+        let stream = handshake(path)?;
+        Ok(Self::spawn(Output::Socket {
+            path: path.to_path_buf(),
+            stream: Some(stream),
+            retry: Instant::now(),
+        }))
+    }
+
+    fn spawn(output: Output) -> Self {
+        let (frames, received) = mpsc::sync_channel(QUEUE_LEN);
+        let writer = thread::spawn(move || write_frames(output, received));
+        DnstapSink {
+            frames: Some(frames),
+            writer: Some(writer),
+            dropped: 0,
+            cut: 0,
+        }
+    }
+}
+
+impl Sink for DnstapSink {
+    fn handle(&mut self, event: &Event) -> Result<(), anyhow::Error> {
+        if event.log.flags & EVENT_FLAG_TCP_RST != 0 {
+            return Ok(());
+        }
+        let (udp, dns) = match (event.udp(), event.dns()) {
+            (Some(udp), Some(dns)) => (udp, dns),
+            _ => return Ok(()),
+        };
+        if (event.frame.len() as u32) < event.log.pkt_len {
+            self.cut += 1;
+            if self.cut.is_power_of_two() {
+                eprintln!(
+                    "dnstap: {} responses longer than --capture-len left out",
+                    self.cut
+                );
+            }
+            return Ok(());
+        }
+        let port = |offset: usize| u16::from_be_bytes([udp[offset], udp[offset + 1]]) as u64;
+        let time = event
+            .received
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let extra = format!(
+            "action={} reason={}",
+            event.log.action,
+            reason_str(event.log.reason)
+        );
+
+        let mut message = Vec::new();
+        put_varint(&mut message, 1, MESSAGE_TYPE_FORWARDER_RESPONSE);
+        put_varint(&mut message, 2, SOCKET_FAMILY_INET);
+        put_varint(&mut message, 3, SOCKET_PROTOCOL_UDP);
+        // the response goes from the upstream to the querying host
+        put_bytes(&mut message, 4, &event.log.ipv4_dst_addr.to_be_bytes());
+        put_bytes(&mut message, 5, &event.log.ipv4_src_addr.to_be_bytes());
+        put_varint(&mut message, 6, port(2));
+        put_varint(&mut message, 7, port(0));
+        put_varint(&mut message, 12, time.as_secs());
+        put_fixed32(&mut message, 13, time.subsec_nanos());
+        put_bytes(&mut message, 14, dns);
+
+        let mut dnstap = Vec::new();
+        put_bytes(&mut dnstap, 1, b"clean-dns");
+        put_bytes(&mut dnstap, 2, env!("CARGO_PKG_VERSION").as_bytes());
+        put_bytes(&mut dnstap, 3, extra.as_bytes());
+        put_bytes(&mut dnstap, 14, &message);
+        put_varint(&mut dnstap, 15, DNSTAP_TYPE_MESSAGE);
+
+        let frames = match &self.frames {
+            Some(frames) => frames,
+            None => return Ok(()),
+        };
+        match frames.try_send(dnstap) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.dropped += 1;
+                // a power of two apart, the warning doesn't add to the load
+                if self.dropped.is_power_of_two() {
+                    eprintln!(
+                        "dnstap writer falling behind, {} frames dropped",
+                        self.dropped
+                    );
+                }
+                Ok(())
+            }
+            Err(TrySendError::Disconnected(_)) => bail!("the dnstap writer exited"),
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), anyhow::Error> {
+        // the writer stops the stream once the queued frames are written
+        self.frames.take();
+        if let Some(writer) = self.writer.take() {
+            if writer.join().is_err() {
+                bail!("the dnstap writer panicked");
+            }
+        }
+        Ok(())
+    }
+}
+
+enum Output {
+    File(BufWriter<File>),
+    Socket {
+        path: PathBuf,
+        stream: Option<UnixStream>,
+        /// when to reconnect, while `stream` is `None`
+        retry: Instant,
+    },
+}
+
+impl Output {
+    fn write(&mut self, frame: &[u8]) -> Result<(), anyhow::Error> {
+        match self {
+            Output::File(out) => write_frame(out, frame),
+            Output::Socket {
+                path,
+                stream,
+                retry,
+            } => {
+                if stream.is_none() {
+                    // the frames sent while the collector is away are lost
+                    if Instant::now() < *retry {
+                        return Ok(());
+                    }
+                    *retry = Instant::now() + RECONNECT_DELAY;
+                    *stream = Some(handshake(path)?);
+                    eprintln!("reconnected to the dnstap collector {}", path.display());
+                }
+                let result = write_frame(stream.as_mut().unwrap(), frame);
+                if result.is_err() {
+                    // reconnect on the next frame
+                    *stream = None;
+                    *retry = Instant::now();
+                }
+                result
+            }
+        }
+    }
+
+    fn finish(self) -> Result<(), anyhow::Error> {
+        match self {
+            Output::File(mut out) => {
+                write_control(&mut out, CONTROL_STOP, false)?;
+                out.flush()?;
+            }
+            Output::Socket {
+                stream: Some(mut stream),
+                ..
+            } => {
+                write_control(&mut stream, CONTROL_STOP, false)?;
+                // the collector acknowledges with FINISH, don't wait on it forever
+                stream.set_read_timeout(Some(FINISH_TIMEOUT))?;
+                if read_control(&mut stream).ok() != Some(CONTROL_FINISH) {
+                    bail!("the dnstap collector didn't finish the stream");
+                }
+            }
+            Output::Socket { stream: None, .. } => {}
+        }
+        Ok(())
+    }
+}
+
+fn write_frames(mut output: Output, frames: Receiver<Vec<u8>>) {
+    let mut failing = false;
+    for frame in frames.iter() {
+        match output.write(&frame) {
+            Ok(()) => failing = false,
+            // report the first of a run of failures only
+            Err(e) if !failing => {
+                eprintln!("dnstap: {:#}", e);
+                failing = true;
+            }
+            Err(_) => {}
+        }
+    }
+    if let Err(e) = output.finish() {
+        eprintln!("dnstap: {:#}", e);
+    }
+}
+
+// bidirectional frame streams: READY, ACCEPT, then START
+fn handshake(path: &Path) -> Result<UnixStream, anyhow::Error> {
+    let mut stream = UnixStream::connect(path)
+        .with_context(|| format!("failed to connect to {}", path.display()))?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    write_control(&mut stream, CONTROL_READY, true)?;
+    if read_control(&mut stream)? != CONTROL_ACCEPT {
+        bail!("{} didn't accept the dnstap stream", path.display());
+    }
+    write_control(&mut stream, CONTROL_START, true)?;
+    Ok(stream)
+}
+
+fn write_frame(out: &mut impl Write, frame: &[u8]) -> Result<(), anyhow::Error> {
+    out.write_all(&(frame.len() as u32).to_be_bytes())?;
+    out.write_all(frame)?;
+    out.flush()?;
+    Ok(())
+}
+
+// a control frame, carrying the content type if `content_type`
+fn write_control(
+    out: &mut impl Write,
+    control: u32,
+    content_type: bool,
+) -> Result<(), anyhow::Error> {
+    let mut frame = Vec::new();
+    frame.extend_from_slice(&control.to_be_bytes());
+    if content_type {
+        frame.extend_from_slice(&CONTROL_FIELD_CONTENT_TYPE.to_be_bytes());
+        frame.extend_from_slice(&(CONTENT_TYPE.len() as u32).to_be_bytes());
+        frame.extend_from_slice(CONTENT_TYPE);
+    }
+    // a zero length escapes the control frame
+    out.write_all(&0u32.to_be_bytes())?;
+    out.write_all(&(frame.len() as u32).to_be_bytes())?;
+    out.write_all(&frame)?;
+    Ok(())
+}
+
+// the type of the control frame read, its fields are skipped
+fn read_control(stream: &mut impl Read) -> Result<u32, anyhow::Error> {
+    let mut escape = [0u8; 8];
+    stream.read_exact(&mut escape)?;
+    let len = u32::from_be_bytes([escape[4], escape[5], escape[6], escape[7]]) as usize;
+    if len > MAX_CONTROL_LEN {
+        bail!("control frame of {} bytes", len);
+    }
+    let mut control = vec![0u8; len];
+    stream.read_exact(&mut control)?;
+    match control.get(..4) {
+        Some(control) if escape[..4] == [0; 4] => {
+            Ok(u32::from_be_bytes(control.try_into().unwrap()))
+        }
+        _ => bail!("malformed control frame"),
+    }
+}
+
+fn varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn put_varint(out: &mut Vec<u8>, field: u64, value: u64) {
+    varint(out, field << 3);
+    varint(out, value);
+}
+
+fn put_fixed32(out: &mut Vec<u8>, field: u64, value: u32) {
+    varint(out, field << 3 | 5);
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_bytes(out: &mut Vec<u8>, field: u64, value: &[u8]) {
+    varint(out, field << 3 | 2);
+    varint(out, value.len() as u64);
+    out.extend_from_slice(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clean_dns_common::PacketLog;
+    use std::{fs, mem, time::SystemTime};
+
+    #[test]
+    fn protobuf() {
+        let mut out = Vec::new();
+        varint(&mut out, 300);
+        assert_eq!(out, [0xac, 0x02]);
+        let mut out = Vec::new();
+        put_varint(&mut out, 15, DNSTAP_TYPE_MESSAGE);
+        put_fixed32(&mut out, 13, 1);
+        put_bytes(&mut out, 1, b"ab");
+        assert_eq!(out, [0x78, 0x01, 0x6d, 1, 0, 0, 0, 0x0a, 2, b'a', b'b']);
+    }
+
+    #[test]
+    fn control() {
+        let mut out = Vec::new();
+        write_control(&mut out, CONTROL_START, true).unwrap();
+        let mut expected = vec![0, 0, 0, 0, 0, 0, 0, 34, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 22];
+        expected.extend_from_slice(b"protobuf:dnstap.Dnstap");
+        assert_eq!(out, expected);
+        assert_eq!(read_control(&mut &out[..]).unwrap(), CONTROL_START);
+
+        let mut out = Vec::new();
+        write_control(&mut out, CONTROL_STOP, false).unwrap();
+        assert_eq!(out, [0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 3]);
+        assert_eq!(read_control(&mut &out[..]).unwrap(), CONTROL_STOP);
+        // a peer can't make us allocate whatever it likes
+        let mut huge = vec![0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff];
+        huge.extend_from_slice(&CONTROL_ACCEPT.to_be_bytes());
+        assert!(read_control(&mut &huge[..]).is_err());
+        // a data frame where a control frame was expected
+        assert!(read_control(&mut &[0, 0, 0, 4, 0, 0, 0, 4, 0, 0, 0, 3][..]).is_err());
+    }
+
+    #[test]
+    fn frame() {
+        let mut out = Vec::new();
+        write_frame(&mut out, &[1, 2, 3]).unwrap();
+        assert_eq!(out, [0, 0, 0, 3, 1, 2, 3]);
+    }
+
+    // a response of `pkt_len` bytes captured up to `cap_len`
+    fn event(pkt_len: usize, cap_len: usize) -> Event {
+        let mut frame = vec![0u8; 14];
+        frame.push(0x45);
+        frame.resize(14 + 20 + 8, 0);
+        frame.extend_from_slice(&[0x12, 0x34, 0x81, 0x80, 0, 0, 0, 0, 0, 0, 0, 0]);
+        frame.resize(pkt_len, 0);
+        frame.truncate(cap_len);
+        let mut log: PacketLog = unsafe { mem::zeroed() };
+        log.pkt_len = pkt_len as u32;
+        log.cap_len = cap_len as u32;
+        Event {
+            log,
+            frame,
+            received: SystemTime::now(),
+        }
+    }
+
+    #[test]
+    fn file() {
+        let path = std::env::temp_dir().join(format!("clean-dns-{}.dnstap", std::process::id()));
+        let mut sink = DnstapSink::create(&path).unwrap();
+        sink.handle(&event(100, 100)).unwrap();
+        // cut off, it wouldn't parse
+        sink.handle(&event(600, 512)).unwrap();
+        sink.finish().unwrap();
+        let file = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let mut reader = &file[..];
+        assert_eq!(read_control(&mut reader).unwrap(), CONTROL_START);
+        let mut len = [0; 4];
+        reader.read_exact(&mut len).unwrap();
+        let mut frame = vec![0; u32::from_be_bytes(len) as usize];
+        reader.read_exact(&mut frame).unwrap();
+        // the whole dns message is in the frame
+        let mut message = Vec::new();
+        put_bytes(&mut message, 14, event(100, 100).dns().unwrap());
+        assert!(frame.windows(message.len()).any(|window| window == message));
+        assert_eq!(read_control(&mut reader).unwrap(), CONTROL_STOP);
+        assert!(reader.is_empty());
+    }
+}
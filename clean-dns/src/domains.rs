@@ -0,0 +1,237 @@
+use clean_dns_common::REASON_NONE;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Write as _,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{events::Event, pipeline::Sink};
+
+// the window slides by a bucket at a time
+const BUCKETS: u32 = 60;
+// names counted per bucket, random subdomain floods would grow it forever
+const MAX_NAMES: usize = 10_000;
+// domains exported in the metrics
+const METRIC_DOMAINS: usize = 20;
+
+#[derive(Debug)]
+struct Bucket {
+    started: Instant,
+    names: HashMap<String, u64>,
+    /// drops of names past `MAX_NAMES`
+    other: u64,
+}
+
+/// Dropped responses per question name over a sliding window.
+#[derive(Debug)]
+pub struct TopDomains {
+    window: Duration,
+    buckets: Mutex<VecDeque<Bucket>>,
+}
+
+impl TopDomains {
+    pub fn new(window: Duration) -> Self {
+        TopDomains {
+            window,
+            buckets: Mutex::default(),
+        }
+    }
+
+    fn observe(&self, name: String, now: Instant) {
+        let mut buckets = self.buckets.lock().unwrap();
+        self.expire(&mut buckets, now);
+        let fresh = match buckets.back() {
+            Some(bucket) => now - bucket.started >= self.window / BUCKETS,
+            None => true,
+        };
+        if fresh {
+            buckets.push_back(Bucket {
+                started: now,
+                names: HashMap::new(),
+                other: 0,
+            });
+        }
+        let bucket = buckets.back_mut().unwrap();
+        if bucket.names.len() < MAX_NAMES || bucket.names.contains_key(&name) {
+            *bucket.names.entry(name).or_default() += 1;
+        } else {
+            bucket.other += 1;
+        }
+    }
+
+    fn expire(&self, buckets: &mut VecDeque<Bucket>, now: Instant) {
+        while let Some(bucket) = buckets.front() {
+            if now - bucket.started < self.window {
+                break;
+            }
+            buckets.pop_front();
+        }
+    }
+
+    /// The `n` names with the most dropped responses in the window, and the
+    /// drops of every name counted, including the ones that didn't fit in the
+    /// counters.
+    pub fn top(&self, n: usize) -> (Vec<(String, u64)>, u64) {
+        self.top_at(n, Instant::now())
+    }
+
+    fn top_at(&self, n: usize, now: Instant) -> (Vec<(String, u64)>, u64) {
+        let mut buckets = self.buckets.lock().unwrap();
+        self.expire(&mut buckets, now);
+        let mut names = HashMap::<&str, u64>::new();
+        let mut total = 0;
+        for bucket in buckets.iter() {
+            for (name, count) in &bucket.names {
+                *names.entry(name).or_default() += count;
+                total += count;
+            }
+            total += bucket.other;
+        }
+        let mut top = names
+            .into_iter()
+            .map(|(name, count)| (name.to_string(), count))
+            .collect::<Vec<_>>();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top.truncate(n);
+        (top, total)
+    }
+
+    /// Render the `n` most targeted names.
+    pub fn report(&self, n: usize) -> String {
+        let (top, total) = self.top(n);
+        let mut report = String::new();
+        let _ = writeln!(
+            report,
+            "dropped responses per domain over the last {}s",
+            self.window.as_secs()
+        );
+        for (name, count) in &top {
+            let _ = writeln!(
+                report,
+                "{:>10} {:>6.2}% {}",
+                count,
+                *count as f64 * 100.0 / total as f64,
+                name
+            );
+        }
+        let other = total - top.iter().map(|(_, count)| count).sum::<u64>();
+        if other > 0 {
+            let _ = writeln!(report, "{:>10} drops of other names", other);
+        }
+        if top.is_empty() {
+            report.push_str("no dropped responses with a captured question\n");
+        }
+        report
+    }
+
+    /// Render the most targeted names in the Prometheus text format.
+    pub fn metrics(&self, out: &mut String) {
+        let name = "clean_dns_domain_drops";
+        let _ = writeln!(
+            out,
+            "# HELP {} Dropped responses of the most targeted domains over the last {}s",
+            name,
+            self.window.as_secs()
+        );
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        for (domain, count) in self.top(METRIC_DOMAINS).0 {
+            // names are escaped already, only the label quoting is left
+            let domain = domain.replace('\\', "\\\\").replace('"', "\\\"");
+            let _ = writeln!(out, "{}{{domain=\"{}\"}} {}", name, domain, count);
+        }
+    }
+}
+
+impl Sink for Arc<TopDomains> {
+    fn handle(&mut self, event: &Event) -> Result<(), anyhow::Error> {
+        if event.log.reason == REASON_NONE {
+            return Ok(());
+        }
+        if let Some(name) = event.qname() {
+            self.observe(name, Instant::now());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::tests::question;
+    use clean_dns_common::REASON_BOGUS_ANSWER;
+
+    fn names(top: &[(String, u64)]) -> Vec<(&str, u64)> {
+        top.iter()
+            .map(|(name, count)| (name.as_str(), *count))
+            .collect()
+    }
+
+    #[test]
+    fn window() {
+        let domains = TopDomains::new(Duration::from_secs(60));
+        let start = Instant::now();
+        let at = |secs: f64| start + Duration::from_secs_f64(secs);
+        domains.observe("a".to_string(), at(0.0));
+        domains.observe("b".to_string(), at(1.5));
+        domains.observe("b".to_string(), at(1.6));
+        domains.observe("a".to_string(), at(30.0));
+        let (top, total) = domains.top_at(10, at(30.0));
+        assert_eq!(names(&top), [("a", 2), ("b", 2)]);
+        assert_eq!(total, 4);
+        // the first bucket slid out of the window
+        let (top, total) = domains.top_at(10, at(61.0));
+        assert_eq!(names(&top), [("b", 2), ("a", 1)]);
+        assert_eq!(total, 3);
+        let (top, _) = domains.top_at(1, at(62.0));
+        assert_eq!(names(&top), [("a", 1)]);
+        assert_eq!(domains.top_at(10, at(91.0)), (Vec::new(), 0));
+    }
+
+    #[test]
+    fn overflow() {
+        let domains = TopDomains::new(Duration::from_secs(60));
+        let now = Instant::now();
+        for i in 0..MAX_NAMES + 1 {
+            domains.observe(format!("{}.example", i), now);
+        }
+        // a counted name still is
+        domains.observe("0.example".to_string(), now);
+        let (top, total) = domains.top_at(1, now);
+        assert_eq!(names(&top), [("0.example", 2)]);
+        assert_eq!(total, MAX_NAMES as u64 + 2);
+    }
+
+    #[test]
+    fn report() {
+        let domains = TopDomains::new(Duration::from_secs(600));
+        let now = Instant::now();
+        for _ in 0..3 {
+            domains.observe("a".to_string(), now);
+        }
+        domains.observe("b".to_string(), now);
+        // the share is of every drop, not only of the listed names
+        assert_eq!(
+            domains.report(1),
+            "dropped responses per domain over the last 600s\n         3  75.00% a\n         1 drops of other names\n"
+        );
+        let mut metrics = String::new();
+        domains.metrics(&mut metrics);
+        assert!(metrics.contains("clean_dns_domain_drops{domain=\"a\"} 3\n"));
+        assert!(TopDomains::new(Duration::from_secs(600))
+            .report(10)
+            .ends_with("no dropped responses with a captured question\n"));
+    }
+
+    #[test]
+    fn sink() {
+        let mut domains = Arc::new(TopDomains::new(Duration::from_secs(60)));
+        let name = b"\x07example\x03com\x00";
+        // passed responses aren't counted
+        domains.handle(&question(name)).unwrap();
+        let mut dropped = question(name);
+        dropped.log.reason = REASON_BOGUS_ANSWER;
+        domains.handle(&dropped).unwrap();
+        assert_eq!(names(&domains.top(10).0), [("example.com", 1)]);
+    }
+}
@@ -0,0 +1,264 @@
+use anyhow::bail;
+use clean_dns_common::{EventHeader, PacketLog, EVENT_MAGIC, EVENT_VERSION};
+use std::{
+    fmt::Write as _,
+    mem,
+    time::{Duration, SystemTime},
+};
+
+/// An event read from `EVENTS`.
+#[derive(Clone)]
+pub struct Event {
+    pub log: PacketLog,
+    /// the first `cap_len` bytes of the frame, empty when it wasn't captured
+    pub frame: Vec<u8>,
+    /// when the packet arrived, from the kernel clock
+    pub received: SystemTime,
+}
+
+const ETH_HLEN: usize = 14;
+const UDP_HLEN: usize = 8;
+// longest name in the wire format
+const MAX_NAME: usize = 255;
+
+impl Event {
+    /// The UDP header and payload of the captured frame.
+    pub fn udp(&self) -> Option<&[u8]> {
+        let ihl = (self.frame.get(ETH_HLEN)? & 0x0f) as usize;
+        let udp = self.frame.get(ETH_HLEN + ihl * 4..)?;
+        if udp.len() < UDP_HLEN {
+            return None;
+        }
+        Some(udp)
+    }
+
+    /// The DNS message of the captured frame.
+    pub fn dns(&self) -> Option<&[u8]> {
+        self.udp()?.get(UDP_HLEN..).filter(|dns| !dns.is_empty())
+    }
+
+    /// The lowercased name of the first question in the presentation format,
+    /// `None` when it wasn't captured in full.
+    pub fn qname(&self) -> Option<String> {
+        let dns = self.dns()?;
+        // QDCOUNT
+        if dns.get(4..6)? == [0, 0] {
+            return None;
+        }
+        let mut name = String::new();
+        let mut offset = 12;
+        loop {
+            let len = *dns.get(offset)? as usize;
+            if len == 0 {
+                break;
+            }
+            // questions aren't compressed, anything but a label is garbage
+            if len > 63 || offset + len + 1 > 12 + MAX_NAME {
+                return None;
+            }
+            let label = dns.get(offset + 1..offset + 1 + len)?;
+            if !name.is_empty() {
+                name.push('.');
+            }
+            for &byte in label {
+                match byte.to_ascii_lowercase() {
+                    // escaped like dig does, forged names can hold anything
+                    b'.' | b'\\' | b'"' => {
+                        name.push('\\');
+                        name.push(byte as char);
+                    }
+                    byte if byte.is_ascii_graphic() => name.push(byte as char),
+                    byte => {
+                        let _ = write!(name, "\\{:03}", byte);
+                    }
+                }
+            }
+            offset += len + 1;
+        }
+        if name.is_empty() {
+            name.push('.');
+        }
+        Some(name)
+    }
+}
+
+/// Decode an event read from `EVENTS`, the `PacketLog` and the captured frame
+/// following it.
+pub fn decode_event(buf: &[u8]) -> Result<Event, anyhow::Error> {
+    let log = decode(buf)?;
+    let start = log.header.len as usize;
+    let end = start + log.cap_len as usize;
+    if end > buf.len() {
+        bail!("captured frame of {} bytes past the event", log.cap_len);
+    }
+    Ok(Event {
+        log,
+        frame: buf[start..end].to_vec(),
+        received: wall_clock(log.ktime_ns),
+    })
+}
+
+/// Convert a `bpf_ktime_get_ns` timestamp, the monotonic clock, to the wall
+/// clock. Events from objects without it get the current time instead.
+pub fn wall_clock(ktime_ns: u64) -> SystemTime {
+    let now = SystemTime::now();
+    if ktime_ns == 0 {
+        return now;
+    }
+    let mut monotonic = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    if unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut monotonic) } != 0 {
+        return now;
+    }
+    let monotonic = Duration::new(monotonic.tv_sec as u64, monotonic.tv_nsec as u32);
+    // the clocks are read back to back, the event is that long ago
+    let age = monotonic.saturating_sub(Duration::from_nanos(ktime_ns));
+    now - age
+}
+
+/// Decode a `PacketLog` read from `EVENTS`.
+///
+/// Events from an older object lack the fields appended since then, they are
+/// left zeroed. Fields a newer object appended are ignored.
+pub fn decode(buf: &[u8]) -> Result<PacketLog, anyhow::Error> {
+    if buf.len() < mem::size_of::<EventHeader>() {
+        bail!("short event of {} bytes", buf.len());
+    }
+    let header = unsafe { (buf.as_ptr() as *const EventHeader).read_unaligned() };
+    if header.magic != EVENT_MAGIC {
+        bail!("bad event magic {:#x}", header.magic);
+    }
+    if header.version != EVENT_VERSION {
+        bail!(
+            "event version {} isn't supported, expected {}",
+            header.version,
+            EVENT_VERSION
+        );
+    }
+    let len = header.len as usize;
+    if len < mem::size_of::<EventHeader>() || len > buf.len() {
+        bail!("bad event length {} in {} bytes", len, buf.len());
+    }
+    let mut bytes = [0u8; mem::size_of::<PacketLog>()];
+    let known = len.min(bytes.len());
+    bytes[..known].copy_from_slice(&buf[..known]);
+    Ok(unsafe { (bytes.as_ptr() as *const PacketLog).read_unaligned() })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::slice;
+
+    fn log(len: usize) -> PacketLog {
+        let mut log: PacketLog = unsafe { mem::zeroed() };
+        log.header = EventHeader::new(len);
+        log
+    }
+
+    fn bytes(log: &PacketLog) -> Vec<u8> {
+        let ptr = log as *const PacketLog as *const u8;
+        unsafe { slice::from_raw_parts(ptr, mem::size_of::<PacketLog>()) }.to_vec()
+    }
+
+    #[test]
+    fn decode_current() {
+        let mut expected = log(mem::size_of::<PacketLog>());
+        expected.reason = 3;
+        expected.ktime_ns = 42;
+        let decoded = decode(&bytes(&expected)).unwrap();
+        assert_eq!(decoded.reason, 3);
+        assert_eq!(decoded.ktime_ns, 42);
+    }
+
+    #[test]
+    fn decode_short() {
+        let event = bytes(&log(mem::size_of::<PacketLog>()));
+        assert!(decode(&event[..mem::size_of::<EventHeader>() - 1]).is_err());
+        // the header claims more than was read
+        assert!(decode(&event[..mem::size_of::<PacketLog>() - 1]).is_err());
+        let mut bad = log(mem::size_of::<PacketLog>());
+        bad.header.magic = 0;
+        assert!(decode(&bytes(&bad)).is_err());
+    }
+
+    #[test]
+    fn decode_old() {
+        // an object from before `ktime_ns` and its padding were appended
+        let mut old = log(0);
+        let len = &old._pad as *const u32 as usize - &old as *const PacketLog as usize;
+        old.header.len = len as u16;
+        old.cap_len = 7;
+        old.ktime_ns = 42;
+        let decoded = decode(&bytes(&old)[..len]).unwrap();
+        assert_eq!(decoded.cap_len, 7);
+        assert_eq!(decoded.ktime_ns, 0);
+    }
+
+    #[test]
+    fn decode_frame() {
+        let len = mem::size_of::<PacketLog>();
+        let mut log = log(len);
+        log.cap_len = 3;
+        let mut buf = bytes(&log);
+        buf.extend_from_slice(&[1, 2, 3]);
+        assert_eq!(decode_event(&buf).unwrap().frame, [1, 2, 3]);
+        assert!(decode_event(&buf[..len + 2]).is_err());
+    }
+
+    #[test]
+    fn received() {
+        // objects without the kernel clock get the time the event was read
+        let before = SystemTime::now();
+        assert!(wall_clock(0) >= before);
+        let mut monotonic = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        assert_eq!(
+            unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut monotonic) },
+            0
+        );
+        let now = Duration::new(monotonic.tv_sec as u64, monotonic.tv_nsec as u32);
+        let ktime_ns = (now - Duration::from_secs(2)).as_nanos() as u64;
+        let age = SystemTime::now()
+            .duration_since(wall_clock(ktime_ns))
+            .unwrap();
+        assert!(age >= Duration::from_secs(2) && age < Duration::from_secs(3));
+    }
+
+    /// An event carrying a frame with a single question for `name`, in the wire format.
+    pub(crate) fn question(name: &[u8]) -> Event {
+        let mut frame = vec![0u8; ETH_HLEN];
+        frame.push(0x45);
+        frame.resize(ETH_HLEN + 20 + UDP_HLEN, 0);
+        frame.extend_from_slice(&[0x12, 0x34, 0x81, 0x80, 0, 1, 0, 0, 0, 0, 0, 0]);
+        frame.extend_from_slice(name);
+        frame.extend_from_slice(&[0, 1, 0, 1]);
+        Event {
+            log: log(mem::size_of::<PacketLog>()),
+            frame,
+            received: SystemTime::now(),
+        }
+    }
+
+    #[test]
+    fn qname() {
+        let mixed = question(b"\x03WwW\x07example\x03com\x00");
+        assert_eq!(mixed.qname().unwrap(), "www.example.com");
+        assert_eq!(question(b"\x00").qname().unwrap(), ".");
+        let escaped = question(b"\x03a.b\x03\x01\\\"\x00");
+        assert_eq!(escaped.qname().unwrap(), "a\\.b.\\001\\\\\\\"");
+        // the name runs past the capture
+        let mut truncated = question(b"\x07example\x00");
+        truncated.frame.truncate(truncated.frame.len() - 6);
+        assert!(truncated.qname().is_none());
+        // a compression pointer
+        assert!(question(b"\xc0\x0c").qname().is_none());
+        let mut no_question = question(b"\x00");
+        no_question.frame[ETH_HLEN + 20 + UDP_HLEN + 5] = 0;
+        assert!(no_question.qname().is_none());
+    }
+}
@@ -0,0 +1,304 @@
+use clean_dns_common::{
+    PacketLog, TtlRange, EVENT_FLAG_ANSWER_NAME, EVENT_FLAG_ANSWER_TYPE, EVENT_FLAG_EDNS_QUERY,
+    EVENT_FLAG_MATCHED, EVENT_FLAG_OPT, EVENT_FLAG_SINGLE_ANSWER, POLICY_ANSWER_NAME,
+    POLICY_ANSWER_TTL, POLICY_ANSWER_TYPE, POLICY_AUTHORITATIVE, POLICY_COOKIES,
+    POLICY_DONT_FRAGMENT, POLICY_IP_ID_ZERO, POLICY_IP_TTL, POLICY_MIN_RTT, POLICY_REQUIRE_EDNS,
+    POLICY_TCP_RST, POLICY_TRUNCATE, POLICY_UNSOLICITED, REASON_NONE,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::Ipv4Addr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::{events::Event, pipeline::Sink, policy::UpstreamSpec};
+
+// below this many responses a fingerprint is just noise
+const MIN_RESPONSES: u64 = 20;
+// IP TTLs seen in fewer responses are left out of the learned range
+const IP_TTL_SHARE: f64 = 0.01;
+// the learned minimum RTT leaves room for the upstream getting faster
+const MIN_RTT_SHARE: u32 = 2;
+
+/// What the responses of an upstream look like.
+#[derive(Debug, Default)]
+pub struct Fingerprint {
+    responses: u64,
+    ip_ttls: BTreeMap<u8, u64>,
+    ip_id_zero: u64,
+    dont_fragment: u64,
+    matched: u64,
+    min_rtt_us: Option<u32>,
+    single_answers: u64,
+    authoritative: u64,
+    answer_type: u64,
+    answer_name: u64,
+    /// single answers to queries carrying an OPT record
+    edns_queries: u64,
+    opt: u64,
+}
+
+impl Fingerprint {
+    fn observe(&mut self, log: &PacketLog) {
+        self.responses += 1;
+        *self.ip_ttls.entry(log.ip_ttl as u8).or_default() += 1;
+        if log.ip_id == 0 {
+            self.ip_id_zero += 1;
+        }
+        if log.ip_frag_off == 0x0040 {
+            self.dont_fragment += 1;
+        }
+        if log.flags & EVENT_FLAG_MATCHED != 0 {
+            self.matched += 1;
+            self.min_rtt_us = Some(
+                self.min_rtt_us
+                    .map_or(log.rtt_us, |rtt| rtt.min(log.rtt_us)),
+            );
+        }
+        if log.flags & EVENT_FLAG_SINGLE_ANSWER != 0 {
+            self.single_answers += 1;
+            if log.dns_flags & 0x0400 != 0 {
+                self.authoritative += 1;
+            }
+            if log.flags & EVENT_FLAG_ANSWER_TYPE != 0 {
+                self.answer_type += 1;
+            }
+            if log.flags & EVENT_FLAG_ANSWER_NAME != 0 {
+                self.answer_name += 1;
+            }
+            // only the queries carrying an OPT record get one back
+            if log.flags & EVENT_FLAG_EDNS_QUERY != 0 {
+                self.edns_queries += 1;
+                if log.flags & EVENT_FLAG_OPT != 0 {
+                    self.opt += 1;
+                }
+            }
+        }
+    }
+
+    /// Enable every check the observed responses would all have passed, the
+    /// IP TTL range without the rare TTLs and the minimum RTT with a margin.
+    fn propose(&self, spec: &mut UpstreamSpec) {
+        let mut flags = 0;
+        if self.ip_id_zero == 0 {
+            flags |= POLICY_IP_ID_ZERO;
+        }
+        if self.dont_fragment == 0 {
+            flags |= POLICY_DONT_FRAGMENT;
+        }
+        if self.matched == self.responses {
+            flags |= POLICY_UNSOLICITED;
+        }
+        if let Some(min_rtt_us) = self.min_rtt_us {
+            flags |= POLICY_MIN_RTT;
+            spec.policy.min_rtt_us = min_rtt_us / MIN_RTT_SHARE;
+        }
+        if self.single_answers >= MIN_RESPONSES {
+            if self.authoritative == 0 {
+                flags |= POLICY_AUTHORITATIVE;
+            }
+            if self.answer_type == self.single_answers {
+                flags |= POLICY_ANSWER_TYPE;
+            }
+            if self.answer_name == self.single_answers {
+                flags |= POLICY_ANSWER_NAME;
+            }
+            if self.edns_queries >= MIN_RESPONSES && self.opt == self.edns_queries {
+                flags |= POLICY_REQUIRE_EDNS;
+            }
+        }
+        let common = self
+            .ip_ttls
+            .iter()
+            .filter(|(_, count)| **count as f64 >= self.responses as f64 * IP_TTL_SHARE)
+            .map(|(ttl, _)| *ttl as u32);
+        if let (Some(min), Some(max)) = (common.clone().min(), common.max()) {
+            flags |= POLICY_IP_TTL;
+            spec.policy.ip_ttl = TtlRange { min, max };
+        }
+        // answer TTL ranges describe the injector, cookies need the egress program,
+        // truncating is an action and RSTs aren't responses, none of them can be
+        // learned from the responses
+        spec.policy.flags = flags
+            | (spec.policy.flags
+                & (POLICY_ANSWER_TTL | POLICY_COOKIES | POLICY_TRUNCATE | POLICY_TCP_RST));
+    }
+}
+
+/// Builds the fingerprints of the upstreams from the events reported while learning.
+#[derive(Debug, Default)]
+pub struct Learner {
+    active: AtomicBool,
+    fingerprints: Mutex<HashMap<Ipv4Addr, Fingerprint>>,
+}
+
+impl Learner {
+    pub fn start(&self) {
+        self.active.store(true, Ordering::Relaxed);
+    }
+
+    pub fn observe(&self, log: &PacketLog) {
+        if !self.active.load(Ordering::Relaxed) {
+            return;
+        }
+        self.fingerprints
+            .lock()
+            .unwrap()
+            .entry(Ipv4Addr::from(log.ipv4_src_addr))
+            .or_default()
+            .observe(log);
+    }
+
+    /// Stop learning and propose a policy for each upstream, keeping the
+    /// configured one for upstreams that didn't answer enough.
+    pub fn stop(&self, upstreams: &[UpstreamSpec]) -> Vec<UpstreamSpec> {
+        self.active.store(false, Ordering::Relaxed);
+        let fingerprints = self.fingerprints.lock().unwrap();
+        upstreams
+            .iter()
+            .map(|upstream| {
+                let mut spec = *upstream;
+                match fingerprints.get(&upstream.addr) {
+                    Some(fingerprint) if fingerprint.responses >= MIN_RESPONSES => {
+                        fingerprint.propose(&mut spec);
+                        println!(
+                            "Learned from {} responses: --upstream {}",
+                            fingerprint.responses, spec
+                        );
+                    }
+                    _ => println!(
+                        "Not enough responses from {}, keeping --upstream {}",
+                        upstream.addr, upstream
+                    ),
+                }
+                spec
+            })
+            .collect()
+    }
+}
+
+impl Sink for Arc<Learner> {
+    fn handle(&mut self, event: &Event) -> Result<(), anyhow::Error> {
+        // the bogus answers dropped while learning were forged
+        if event.log.reason != REASON_NONE {
+            return Ok(());
+        }
+        self.observe(&event.log);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clean_dns_common::{POLICY_DEFAULT, REASON_BOGUS_ANSWER};
+    use std::{mem, time::SystemTime};
+
+    const UPSTREAM: Ipv4Addr = Ipv4Addr::new(8, 8, 8, 8);
+
+    // a matched single answer response passing every check
+    fn response(ip_ttl: u32, rtt_us: u32) -> PacketLog {
+        let mut log: PacketLog = unsafe { mem::zeroed() };
+        log.ipv4_src_addr = u32::from(UPSTREAM);
+        log.ip_ttl = ip_ttl;
+        log.ip_id = 1234;
+        log.rtt_us = rtt_us;
+        log.flags = EVENT_FLAG_MATCHED
+            | EVENT_FLAG_SINGLE_ANSWER
+            | EVENT_FLAG_ANSWER_TYPE
+            | EVENT_FLAG_ANSWER_NAME
+            | EVENT_FLAG_EDNS_QUERY
+            | EVENT_FLAG_OPT;
+        log
+    }
+
+    fn learn(configured: &str, responses: &[PacketLog]) -> UpstreamSpec {
+        let learner = Learner::default();
+        learner.start();
+        for log in responses {
+            learner.observe(log);
+        }
+        learner.stop(&[configured.parse().unwrap()])[0]
+    }
+
+    #[test]
+    fn propose() {
+        let mut responses = (0..100)
+            .map(|i| response(56, 10_000 + i))
+            .collect::<Vec<_>>();
+        // a single odd hop count is left out of the range
+        responses.push(response(200, 20_000));
+        let spec = learn("8.8.8.8,cookies", &responses);
+        assert_eq!(
+            spec.to_string(),
+            "8.8.8.8,ip-id,df,aa,answer-type,answer-name,edns,unsolicited,cookies,ip-ttl=56,min-rtt=5000us"
+        );
+
+        // one answer of another type, one unmatched response and a DF response
+        responses[0].flags &= !EVENT_FLAG_ANSWER_TYPE;
+        responses[1].flags &= !EVENT_FLAG_MATCHED;
+        responses[2].ip_frag_off = 0x0040;
+        let spec = learn("8.8.8.8,cookies", &responses);
+        assert_eq!(
+            spec.to_string(),
+            "8.8.8.8,ip-id,aa,answer-name,edns,cookies,ip-ttl=56,min-rtt=5000us"
+        );
+    }
+
+    #[test]
+    fn edns() {
+        // queries without an OPT record don't get one back
+        let mut responses = (0..100).map(|_| response(56, 10_000)).collect::<Vec<_>>();
+        for log in &mut responses[..90] {
+            log.flags &= !(EVENT_FLAG_EDNS_QUERY | EVENT_FLAG_OPT);
+        }
+        assert!(learn("8.8.8.8", &responses).policy.flags & POLICY_REQUIRE_EDNS == 0);
+        for log in &mut responses[..90] {
+            log.flags |= EVENT_FLAG_EDNS_QUERY | EVENT_FLAG_OPT;
+        }
+        assert!(learn("8.8.8.8", &responses).policy.flags & POLICY_REQUIRE_EDNS != 0);
+        responses[0].flags &= !EVENT_FLAG_OPT;
+        assert!(learn("8.8.8.8", &responses).policy.flags & POLICY_REQUIRE_EDNS == 0);
+    }
+
+    #[test]
+    fn not_enough() {
+        let responses = (0..MIN_RESPONSES as u32 - 1)
+            .map(|_| response(56, 10_000))
+            .collect::<Vec<_>>();
+        let spec = learn("8.8.8.8,answer-type", &responses);
+        assert_eq!(spec.policy.flags, POLICY_ANSWER_TYPE);
+    }
+
+    #[test]
+    fn sink() {
+        let mut learner = Arc::new(Learner::default());
+        let event = |log| Event {
+            log,
+            frame: Vec::new(),
+            received: SystemTime::now(),
+        };
+        // nothing is learned before the start
+        learner.handle(&event(response(56, 10_000))).unwrap();
+        learner.start();
+        for _ in 0..MIN_RESPONSES {
+            learner.handle(&event(response(56, 10_000))).unwrap();
+        }
+        // the forged answers dropped while learning are left out
+        let mut bogus = response(3, 10);
+        bogus.reason = REASON_BOGUS_ANSWER;
+        learner.handle(&event(bogus)).unwrap();
+        let fingerprints = learner.fingerprints.lock().unwrap();
+        let fingerprint = &fingerprints[&UPSTREAM];
+        assert_eq!(fingerprint.responses, MIN_RESPONSES);
+        assert_eq!(fingerprint.min_rtt_us, Some(10_000));
+        drop(fingerprints);
+        assert_eq!(
+            learner.stop(&[UpstreamSpec::new(UPSTREAM)])[0].policy.flags & POLICY_DEFAULT,
+            POLICY_DEFAULT
+        );
+    }
+}
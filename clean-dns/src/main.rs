@@ -1,75 +1,505 @@
+mod abi;
+mod bogus;
+mod control;
+mod cookie;
+mod diagnostics;
+mod dnstap;
+mod domains;
+mod events;
+mod learn;
+mod pcap;
+mod pipeline;
+mod policy;
+mod probe;
+mod reader;
+mod stats;
+mod summary;
+
+use anyhow::Context as _;
+#[cfg(feature = "embed")]
+use aya::include_bytes_aligned;
 use aya::{
-    include_bytes_aligned,
-    maps::{perf::AsyncPerfEventArray, HashMap},
-    programs::{Xdp, XdpFlags},
+    maps::{
+        lpm_trie::{Key, LpmTrie},
+        perf::AsyncPerfEventArray,
+        Array, HashMap, PerCpuHashMap,
+    },
+    programs::{tc, SchedClassifier, TcAttachType, Xdp},
     util::online_cpus,
     Bpf,
 };
-use bytes::BytesMut;
-use clean_dns_common::PacketLog;
+use bogus::BogusNet;
+use clean_dns_common::{
+    CaptureConfig, EventRate, UpstreamPolicy, COOKIE_LEN, MAX_CAPTURE, POLICY_COOKIES, POLICY_LEARN,
+};
+use cookie::Secret;
+use dnstap::DnstapSink;
+use domains::TopDomains;
+use learn::Learner;
+use pcap::PcapSink;
+use pipeline::{LogSink, Sink};
+use policy::{UpstreamSpec, EGRESS_CHECKS};
+use probe::Features;
 use std::{
     convert::{TryFrom, TryInto},
-    net::{self, Ipv4Addr},
+    fs,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 use structopt::StructOpt;
-use tokio::{self, signal, task};
+use summary::Summarizer;
+use tokio::{self, signal, sync::mpsc, task, time};
 
 #[derive(Debug, StructOpt)]
 struct Opt {
     #[structopt(short, long, default_value = "eth0")]
     iface: String,
+    /// Unix socket the daemon answers commands on
+    #[structopt(long, default_value = "/run/clean-dns.sock", parse(from_os_str))]
+    control: PathBuf,
+    /// File of bogus answer addresses, either dnsmasq `bogus-nxdomain=` lines or plain IPs/prefixes
+    #[structopt(long, parse(from_os_str))]
+    bogus: Vec<PathBuf>,
+    /// Upstream to filter as `ADDR[,CHECK...]`, checks: default, none, ip-id, df, aa, answer-type, answer-name, edns, unsolicited, duplicates, 0x20, cookies, truncate, tcp-rst, ip-ttl=MIN[-MAX], ttl=MIN[-MAX], min-rtt=N(ms|us)
+    #[structopt(long = "upstream")]
+    upstreams: Vec<UpstreamSpec>,
+    /// Only observe the upstreams for this many seconds, then propose a policy for each of them
+    #[structopt(long)]
+    learn: Option<u64>,
+    /// Install the learned policies instead of going back to the configured ones
+    #[structopt(long, requires = "learn")]
+    learn_install: bool,
+    /// Load the eBPF programs from this object file instead of the embedded ones
+    #[structopt(long, parse(from_os_str))]
+    bpf_object: Option<PathBuf>,
+    /// Also drop responses conntrack has no outgoing flow for, on kernels with the XDP conntrack kfuncs
+    /// (a --bpf-object uses conntrack if it was built with it)
+    #[structopt(long)]
+    conntrack: bool,
+    /// Write the verifier log and what the kernel supports to this file when the filter is rejected
+    #[structopt(long, parse(from_os_str))]
+    verifier_log: Option<PathBuf>,
+    /// Most events output per second for each upstream and reason, the others are only counted
+    #[structopt(long)]
+    event_rate: Option<u64>,
+    /// Events an upstream and reason can output at once before the rate applies
+    #[structopt(long, default_value = "10")]
+    event_burst: u64,
+    /// Write the frames of the packets given a verdict to this pcapng file
+    #[structopt(long, parse(from_os_str))]
+    pcap: Option<PathBuf>,
+    /// Write the classified responses as dnstap frames to this file
+    #[structopt(long, parse(from_os_str))]
+    dnstap: Option<PathBuf>,
+    /// Stream the classified responses as dnstap frames to the collector on
+    /// this unix socket
+    #[structopt(long, parse(from_os_str), conflicts_with = "dnstap")]
+    dnstap_socket: Option<PathBuf>,
+    /// Print a summary of the events every this many seconds
+    #[structopt(long)]
+    summary_interval: Option<u64>,
+    /// Don't print a line per event
+    #[structopt(long)]
+    no_packet_log: bool,
+    /// Seconds of dropped responses the top domains are counted over
+    #[structopt(long, default_value = "600")]
+    top_domains_window: u64,
+    /// Bytes of each frame captured for --pcap, --dnstap and the targeted domains, 0 for none
+    #[structopt(long, default_value = "256")]
+    capture_len: u32,
+    /// Perf buffers filled by each read of the events
+    #[structopt(long, default_value = "10")]
+    event_buffers: usize,
+    /// Initial size in bytes of each event buffer
+    #[structopt(long, default_value = "1024")]
+    event_buffer_size: usize,
+    /// Pages of the per-cpu perf rings, raise it if events get lost
+    #[structopt(long)]
+    perf_pages: Option<usize>,
+    /// Upstream TCP port whose RSTs the `tcp-rst` check looks at
+    #[structopt(long = "tcp-port", default_value = "53")]
+    tcp_ports: Vec<u16>,
+    /// File keeping the DNS cookie secret across restarts, created if missing
+    #[structopt(long, parse(from_os_str))]
+    cookie_secret: Option<PathBuf>,
+    /// Replace the DNS cookie secret every this many seconds
+    #[structopt(long)]
+    cookie_rotate: Option<u64>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+/// Commands sent to a running daemon, it runs the filter when none is given.
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Show the observed IP TTL distribution per upstream
+    TtlStats,
+    /// Show the daemon counters in the Prometheus text format
+    Metrics,
+    /// Show the domains with the most dropped responses
+    TopDomains {
+        /// How many domains to show
+        #[structopt(default_value = "20")]
+        count: usize,
+    },
+}
+
+/// Where the eBPF programs are loaded from.
+enum BpfObject {
+    #[cfg_attr(not(feature = "embed"), allow(dead_code))]
+    Embedded(&'static [u8]),
+    File(PathBuf),
+}
+
+// This will include youe eBPF object files as raw bytes at compile-time and load one at
+// runtime. This approach is recommended for most real-world use cases, `--bpf-object`
+// loads one from a file at runtime instead.
+#[cfg(all(feature = "embed", debug_assertions))]
+fn bpf_object(conntrack: bool) -> &'static [u8] {
+    if conntrack {
+        include_bytes_aligned!("../../target/conntrack/bpfel-unknown-none/debug/clean-dns")
+    } else {
+        include_bytes_aligned!("../../target/bpfel-unknown-none/debug/clean-dns")
+    }
+}
+
+#[cfg(all(feature = "embed", not(debug_assertions)))]
+fn bpf_object(conntrack: bool) -> &'static [u8] {
+    if conntrack {
+        include_bytes_aligned!("../../target/conntrack/bpfel-unknown-none/release/clean-dns")
+    } else {
+        include_bytes_aligned!("../../target/bpfel-unknown-none/release/clean-dns")
+    }
+}
+
+/// Load the embedded object, the conntrack variant first if asked for.
+#[cfg(feature = "embed")]
+fn load_embedded(opt: &Opt, features: &Features) -> Result<(Bpf, &'static str), anyhow::Error> {
+    if opt.conntrack {
+        // a rejected conntrack variant is expected on older kernels, only the
+        // plain filter failing too is worth a bundle
+        load_xdp(&BpfObject::Embedded(bpf_object(true)), opt, features, None).or_else(|e| {
+            eprintln!(
+                "conntrack mode unavailable, falling back to the plain filter: {:#}",
+                e
+            );
+            let verifier_log = opt.verifier_log.as_deref();
+            load_xdp(
+                &BpfObject::Embedded(bpf_object(false)),
+                opt,
+                features,
+                verifier_log,
+            )
+        })
+    } else {
+        let verifier_log = opt.verifier_log.as_deref();
+        load_xdp(
+            &BpfObject::Embedded(bpf_object(false)),
+            opt,
+            features,
+            verifier_log,
+        )
+    }
+}
+
+#[cfg(not(feature = "embed"))]
+fn load_embedded(_: &Opt, _: &Features) -> Result<(Bpf, &'static str), anyhow::Error> {
+    Err(anyhow::anyhow!(
+        "built without the embedded eBPF object, pass --bpf-object"
+    ))
+}
+
+/// Load `object` and attach its XDP program to the interface, returning the XDP mode.
+///
+/// A rejected program writes the diagnostic bundle to `verifier_log` if given.
+fn load_xdp(
+    object: &BpfObject,
+    opt: &Opt,
+    features: &Features,
+    verifier_log: Option<&Path>,
+) -> Result<(Bpf, &'static str), anyhow::Error> {
+    let mut bpf = match object {
+        BpfObject::Embedded(data) => {
+            abi::check(data)?;
+            Bpf::load(data)?
+        }
+        BpfObject::File(path) => {
+            let data =
+                fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+            abi::check(&data).with_context(|| format!("{} can't be used", path.display()))?;
+            Bpf::load_file(path)?
+        }
+    };
+    let program: &mut Xdp = bpf.program_mut("clean_dns").unwrap().try_into()?;
+    program
+        .load()
+        .map_err(|e| diagnostics::load_error(verifier_log, features, "clean_dns", e))?;
+    let mode = probe::attach_xdp(program, &opt.iface)?;
+    Ok((bpf, mode))
 }
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
     let opt = Opt::from_args();
-    // This will include youe eBPF object file as raw bytes at compile-time and load it at
-    // runtime. This approach is recommended for most real-world use cases. If you would
-    // like to specify the eBPF program at runtime rather than at compile-time, you can
-    // reach for `Bpf::load_file` instead.
-    #[cfg(debug_assertions)]
-    let mut bpf = Bpf::load(include_bytes_aligned!(
-        "../../target/bpfel-unknown-none/debug/clean-dns"
-    ))?;
-    #[cfg(not(debug_assertions))]
-    let mut bpf = Bpf::load(include_bytes_aligned!(
-        "../../target/bpfel-unknown-none/release/clean-dns"
-    ))?;
-    let program: &mut Xdp = bpf.program_mut("clean_dns").unwrap().try_into()?;
-    program.load()?;
-    program.attach(&opt.iface, XdpFlags::default())?;
-    let mut perf_array = AsyncPerfEventArray::try_from(bpf.map_mut("EVENTS")?)?;
-    let mut blocklist: HashMap<_, u32, u32> = HashMap::try_from(bpf.map_mut("BLOCKLIST")?)?;
-    blocklist.insert(Ipv4Addr::new(8, 8, 8, 8).try_into()?, 0, 0)?;
-    blocklist.insert(Ipv4Addr::new(1, 1, 1, 1).try_into()?, 0, 0)?;
+    if let Some(command) = &opt.command {
+        let request = match command {
+            Command::TtlStats => "ttl-stats".to_string(),
+            Command::Metrics => "metrics".to_string(),
+            Command::TopDomains { count } => format!("top-domains {}", count),
+        };
+        print!("{}", control::request(&opt.control, &request).await?);
+        return Ok(());
+    }
+    // a loaded object was built with or without conntrack, there is nothing to pick
+    if opt.conntrack && opt.bpf_object.is_some() {
+        anyhow::bail!(
+            "--conntrack picks one of the embedded objects, load the conntrack build of the --bpf-object instead"
+        );
+    }
+    let features = Features::probe();
+    print!("{}", features.report());
+    features.check()?;
+    // only the bogus answer sets are stored in LPM tries
+    if !features.lpm_trie && !opt.bogus.is_empty() {
+        anyhow::bail!("the kernel can't create LPM trie maps, --bogus needs them");
+    }
+    let (mut bpf, xdp_mode) = match &opt.bpf_object {
+        // the object decides whether conntrack is used
+        Some(path) => load_xdp(
+            &BpfObject::File(path.clone()),
+            &opt,
+            &features,
+            opt.verifier_log.as_deref(),
+        )?,
+        None => load_embedded(&opt, &features)?,
+    };
+    println!("XDP mode: {}", xdp_mode);
+    // error adding clsact to the interface if it is already added is harmless
+    // the full cleanup can be done with 'sudo tc qdisc del dev eth0 clsact'.
+    let _ = tc::qdisc_add_clsact(&opt.iface);
+    let egress = (|| -> Result<(), anyhow::Error> {
+        let program: &mut SchedClassifier =
+            bpf.program_mut("clean_dns_egress").unwrap().try_into()?;
+        // the filter runs without it, no bundle for a fallback
+        program
+            .load()
+            .map_err(|e| diagnostics::load_error(None, &features, "clean_dns_egress", e))?;
+        program.attach(&opt.iface, TcAttachType::Egress)?;
+        Ok(())
+    })();
+    if let Err(e) = &egress {
+        eprintln!(
+            "TC egress unavailable ({:#}), disabling the checks needing the queries",
+            e
+        );
+    }
+    println!("TC egress: {}", if egress.is_ok() { "yes" } else { "no" });
+    let perf_array = AsyncPerfEventArray::try_from(bpf.map_mut("EVENTS")?)?;
+    let mut blocklist: HashMap<_, u32, UpstreamPolicy> =
+        HashMap::try_from(bpf.map_mut("BLOCKLIST")?)?;
+    let mut upstreams = opt.upstreams.clone();
+    if upstreams.is_empty() {
+        upstreams.push(UpstreamSpec::new(Ipv4Addr::new(8, 8, 8, 8)));
+        upstreams.push(UpstreamSpec::new(Ipv4Addr::new(1, 1, 1, 1)));
+    }
+    if egress.is_err() {
+        for upstream in &mut upstreams {
+            upstream.policy.flags &= !EGRESS_CHECKS;
+        }
+    }
+    let learner = Arc::new(Learner::default());
+    for upstream in &upstreams {
+        let policy = if opt.learn.is_some() {
+            UpstreamPolicy::new(POLICY_LEARN)
+        } else {
+            upstream.policy
+        };
+        blocklist.insert(u32::from(upstream.addr), policy, 0)?;
+    }
+    if opt.learn.is_some() {
+        learner.start();
+    }
 
-    println!("Waiting for Ctrl-C...");
-    for cpu_id in online_cpus()? {
-        let mut buf = perf_array.open(cpu_id, None)?;
+    let mut tcp_ports: HashMap<_, u16, u32> = HashMap::try_from(bpf.map_mut("TCP_PORTS")?)?;
+    for port in &opt.tcp_ports {
+        tcp_ports.insert(*port, 1, 0)?;
+    }
 
+    let mut cookies: HashMap<_, u32, [u8; COOKIE_LEN]> =
+        HashMap::try_from(bpf.map_mut("COOKIES")?)?;
+    let secret = match &opt.cookie_secret {
+        Some(path) => Secret::load(path)?,
+        None => Secret::random()?,
+    };
+    let cookie_upstreams: Vec<_> = upstreams
+        .iter()
+        .filter(|upstream| upstream.policy.flags & POLICY_COOKIES != 0)
+        .map(|upstream| upstream.addr)
+        .collect();
+    for upstream in &cookie_upstreams {
+        cookies.insert(u32::from(*upstream), secret.client_cookie(*upstream), 0)?;
+    }
+    if let (Some(secs), false) = (opt.cookie_rotate, cookie_upstreams.is_empty()) {
+        let path = opt.cookie_secret.clone();
         task::spawn(async move {
-            let mut buffers = (0..10)
-                .map(|_| BytesMut::with_capacity(1024))
-                .collect::<Vec<_>>();
-
+            let mut interval = time::interval(Duration::from_secs(secs));
+            // the first tick completes immediately
+            interval.tick().await;
             loop {
-                let events = buf.read_events(&mut buffers).await.unwrap();
-                for i in 0..events.read {
-                    let buf = &mut buffers[i];
-                    let ptr = buf.as_ptr() as *const PacketLog;
-                    let data = unsafe { ptr.read_unaligned() };
-                    let src_addr = net::Ipv4Addr::from(data.ipv4_src_addr);
-                    let dst_addr = net::Ipv4Addr::from(data.ipv4_dst_addr);
-                    println!(
-                        "LOG: SRC {}, DST {}, ACTION {}",
-                        src_addr, dst_addr, data.action
-                    );
+                interval.tick().await;
+                // queries in flight keep the cookie they were sent with
+                let rotated = Secret::random().and_then(|secret| {
+                    if let Some(path) = &path {
+                        secret.save(path)?;
+                    }
+                    for upstream in &cookie_upstreams {
+                        cookies.insert(u32::from(*upstream), secret.client_cookie(*upstream), 0)?;
+                    }
+                    Ok(())
+                });
+                if let Err(e) = rotated {
+                    eprintln!("failed to rotate the cookie secret: {:#}", e);
                 }
             }
         });
     }
-    signal::ctrl_c().await.expect("failed to listen for event");
+
+    let mut bogus_v4: LpmTrie<_, u32, u32> = LpmTrie::try_from(bpf.map_mut("BOGUS_V4")?)?;
+    let mut bogus_v6: LpmTrie<_, [u8; 16], u32> = LpmTrie::try_from(bpf.map_mut("BOGUS_V6")?)?;
+    for path in &opt.bogus {
+        let nets = bogus::load(path)?;
+        for net in &nets {
+            match *net {
+                BogusNet::V4(addr, prefix) => {
+                    bogus_v4.insert(&Key::new(prefix, u32::from_ne_bytes(addr.octets())), 1, 0)?
+                }
+                BogusNet::V6(addr, prefix) => {
+                    bogus_v6.insert(&Key::new(prefix, addr.octets()), 1, 0)?
+                }
+            }
+        }
+        println!(
+            "Loaded {} bogus answers from {}",
+            nets.len(),
+            path.display()
+        );
+    }
+
+    let mut event_rate: Array<_, EventRate> = Array::try_from(bpf.map_mut("EVENT_RATE")?)?;
+    let rate = match opt.event_rate {
+        Some(per_sec) if per_sec > 0 => {
+            let cost_ns = 1_000_000_000 / per_sec;
+            EventRate {
+                cost_ns,
+                burst_ns: cost_ns * opt.event_burst.max(1),
+            }
+        }
+        _ => EventRate {
+            cost_ns: 0,
+            burst_ns: 0,
+        },
+    };
+    event_rate.set(0, rate, 0)?;
+
+    let reader_options = reader::Options {
+        buffers: opt.event_buffers,
+        buffer_size: opt.event_buffer_size,
+        pages: opt.perf_pages,
+    };
+    let top_domains = Arc::new(TopDomains::new(Duration::from_secs(
+        opt.top_domains_window.max(1),
+    )));
+    let mut sinks: Vec<Box<dyn Sink>> =
+        vec![Box::new(learner.clone()), Box::new(top_domains.clone())];
+    if !opt.no_packet_log {
+        sinks.push(Box::new(LogSink));
+    }
+    if let Some(secs) = opt.summary_interval {
+        let summarizer = Arc::new(Summarizer::default());
+        sinks.push(Box::new(summarizer.clone()));
+        task::spawn(async move {
+            let interval = Duration::from_secs(secs.max(1));
+            let mut ticks = time::interval(interval);
+            // the first tick completes immediately
+            ticks.tick().await;
+            loop {
+                ticks.tick().await;
+                print!("{}", summarizer.summary(interval));
+            }
+        });
+    }
+    if let Some(path) = &opt.pcap {
+        sinks.push(Box::new(PcapSink::create(path)?));
+    }
+    if let Some(path) = &opt.dnstap {
+        sinks.push(Box::new(DnstapSink::create(path)?));
+    }
+    if let Some(path) = &opt.dnstap_socket {
+        sinks.push(Box::new(DnstapSink::connect(path)?));
+    }
+    // the top domains want the question of every response given a verdict,
+    // only dnstap wants the responses that passed
+    let dnstap = opt.dnstap.is_some() || opt.dnstap_socket.is_some();
+    let capture_len = opt.capture_len.min(MAX_CAPTURE);
+    let mut capture: Array<_, CaptureConfig> = Array::try_from(bpf.map_mut("CAPTURE")?)?;
+    capture.set(
+        0,
+        CaptureConfig {
+            verdict_len: capture_len,
+            pass_len: if dnstap { capture_len } else { 0 },
+        },
+        0,
+    )?;
+    let (events_tx, events_rx) = mpsc::channel(pipeline::QUEUE_LEN);
+    let pipeline = pipeline::run(events_rx, sinks);
+    let event_stats = reader::spawn(perf_array, &online_cpus()?, reader_options, events_tx);
+
+    let ip_ttl_stats = Mutex::new(PerCpuHashMap::try_from(bpf.map("IP_TTL_STATS")?)?);
+    control::serve(
+        opt.control.clone(),
+        Arc::new(move |request: &str| {
+            let (command, argument) = request.split_once(' ').unwrap_or((request, ""));
+            match command {
+                "ttl-stats" => stats::ip_ttl_report(&ip_ttl_stats.lock().unwrap()),
+                "metrics" => {
+                    let mut metrics = String::new();
+                    event_stats.metrics(&mut metrics);
+                    top_domains.metrics(&mut metrics);
+                    Ok(metrics)
+                }
+                "top-domains" => {
+                    let count = argument
+                        .parse()
+                        .with_context(|| format!("bad domain count `{}`", argument))?;
+                    Ok(top_domains.report(count))
+                }
+                _ => Err(anyhow::anyhow!("unknown command `{}`", request)),
+            }
+        }),
+    )?;
+
+    println!("Waiting for Ctrl-C...");
+    if let Some(secs) = opt.learn {
+        println!("Learning the upstreams for {}s...", secs);
+        tokio::select! {
+            _ = time::sleep(Duration::from_secs(secs)) => {
+                let learned = learner.stop(&upstreams);
+                let upstreams = if opt.learn_install { &learned } else { &upstreams };
+                for upstream in upstreams {
+                    blocklist.insert(u32::from(upstream.addr), upstream.policy, 0)?;
+                }
+                signal::ctrl_c().await.expect("failed to listen for event");
+            }
+            _ = signal::ctrl_c() => {}
+        }
+    } else {
+        signal::ctrl_c().await.expect("failed to listen for event");
+    }
     println!("Exiting...");
+    pipeline.stop().await;
+    let _ = fs::remove_file(&opt.control);
 
     Ok(())
 }
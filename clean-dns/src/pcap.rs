@@ -0,0 +1,176 @@
+use anyhow::Context as _;
+use clean_dns_common::{reason_str, MAX_CAPTURE, REASON_NONE};
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    time::UNIX_EPOCH,
+};
+
+use crate::{events::Event, pipeline::Sink};
+
+const SECTION_HEADER_BLOCK: u32 = 0x0a0d_0d0a;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 1;
+const ENHANCED_PACKET_BLOCK: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+const LINKTYPE_ETHERNET: u16 = 1;
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+
+/// Writes the captured frames to a pcapng file, the verdict of each one as
+/// its comment.
+pub struct PcapSink {
+    out: BufWriter<File>,
+}
+
+impl PcapSink {
+    pub fn create(path: &Path) -> Result<Self, anyhow::Error> {
+        let file =
+            File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+        let mut out = BufWriter::new(file);
+        // version 1.0, unknown section length
+        let mut section = Vec::new();
+        section.extend_from_slice(&BYTE_ORDER_MAGIC.to_ne_bytes());
+        section.extend_from_slice(&1u16.to_ne_bytes());
+        section.extend_from_slice(&0u16.to_ne_bytes());
+        section.extend_from_slice(&(-1i64).to_ne_bytes());
+        write_block(&mut out, SECTION_HEADER_BLOCK, &section)?;
+        // microsecond timestamps, the default resolution
+        let mut interface = Vec::new();
+        interface.extend_from_slice(&LINKTYPE_ETHERNET.to_ne_bytes());
+        interface.extend_from_slice(&0u16.to_ne_bytes());
+        interface.extend_from_slice(&MAX_CAPTURE.to_ne_bytes());
+        write_block(&mut out, INTERFACE_DESCRIPTION_BLOCK, &interface)?;
+        out.flush()?;
+        Ok(PcapSink { out })
+    }
+}
+
+impl Sink for PcapSink {
+    fn handle(&mut self, event: &Event) -> Result<(), anyhow::Error> {
+        // dnstap also captures the passed responses
+        if event.frame.is_empty() || event.log.reason == REASON_NONE {
+            return Ok(());
+        }
+        let micros = event
+            .received
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let comment = format!(
+            "action={} reason={}",
+            event.log.action,
+            reason_str(event.log.reason)
+        );
+        let mut packet = Vec::new();
+        packet.extend_from_slice(&0u32.to_ne_bytes());
+        packet.extend_from_slice(&((micros >> 32) as u32).to_ne_bytes());
+        packet.extend_from_slice(&(micros as u32).to_ne_bytes());
+        packet.extend_from_slice(&(event.frame.len() as u32).to_ne_bytes());
+        packet.extend_from_slice(&event.log.pkt_len.to_ne_bytes());
+        push_padded(&mut packet, &event.frame);
+        packet.extend_from_slice(&OPT_COMMENT.to_ne_bytes());
+        packet.extend_from_slice(&(comment.len() as u16).to_ne_bytes());
+        push_padded(&mut packet, comment.as_bytes());
+        packet.extend_from_slice(&OPT_ENDOFOPT.to_ne_bytes());
+        packet.extend_from_slice(&0u16.to_ne_bytes());
+        write_block(&mut self.out, ENHANCED_PACKET_BLOCK, &packet)?;
+        // the file is read while clean-dns runs, don't keep frames buffered
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+// append `data` padded to 32 bits
+fn push_padded(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend_from_slice(data);
+    out.resize(out.len() + (4 - data.len() % 4) % 4, 0);
+}
+
+fn write_block(out: &mut impl Write, block_type: u32, body: &[u8]) -> io::Result<()> {
+    let len = (12 + body.len()) as u32;
+    out.write_all(&block_type.to_ne_bytes())?;
+    out.write_all(&len.to_ne_bytes())?;
+    out.write_all(body)?;
+    out.write_all(&len.to_ne_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clean_dns_common::{PacketLog, REASON_BOGUS_ANSWER};
+    use std::{fs, mem, time::SystemTime};
+
+    #[test]
+    fn padding() {
+        let mut out = Vec::new();
+        push_padded(&mut out, b"abcde");
+        assert_eq!(out, b"abcde\0\0\0");
+        push_padded(&mut out, b"abcd");
+        assert_eq!(out.len(), 12);
+    }
+
+    #[test]
+    fn block() {
+        let mut out = Vec::new();
+        write_block(&mut out, ENHANCED_PACKET_BLOCK, &[1, 2, 3, 4]).unwrap();
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&ENHANCED_PACKET_BLOCK.to_ne_bytes());
+        expected.extend_from_slice(&16u32.to_ne_bytes());
+        expected.extend_from_slice(&[1, 2, 3, 4]);
+        expected.extend_from_slice(&16u32.to_ne_bytes());
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn file() {
+        let path = std::env::temp_dir().join(format!("clean-dns-{}.pcapng", std::process::id()));
+        let mut sink = PcapSink::create(&path).unwrap();
+        let mut log: PacketLog = unsafe { mem::zeroed() };
+        log.reason = REASON_BOGUS_ANSWER;
+        log.pkt_len = 100;
+        let event = Event {
+            log,
+            frame: vec![0xaa; 42],
+            received: SystemTime::now(),
+        };
+        sink.handle(&event).unwrap();
+        // passed responses aren't captured
+        log.reason = REASON_NONE;
+        sink.handle(&Event { log, ..event }).unwrap();
+        drop(sink);
+        let file = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let u32_at =
+            |offset: usize| u32::from_ne_bytes(file[offset..offset + 4].try_into().unwrap());
+        let mut blocks = Vec::new();
+        let mut offset = 0;
+        while offset < file.len() {
+            let len = u32_at(offset + 4) as usize;
+            assert_eq!(len % 4, 0);
+            assert_eq!(u32_at(offset + len - 4) as usize, len);
+            blocks.push((u32_at(offset), offset));
+            offset += len;
+        }
+        assert_eq!(offset, file.len());
+        let types = blocks
+            .iter()
+            .map(|(block_type, _)| *block_type)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            types,
+            [
+                SECTION_HEADER_BLOCK,
+                INTERFACE_DESCRIPTION_BLOCK,
+                ENHANCED_PACKET_BLOCK
+            ]
+        );
+        let packet = blocks[2].1 + 8;
+        // captured and original lengths
+        assert_eq!(u32_at(packet + 12), 42);
+        assert_eq!(u32_at(packet + 16), 100);
+        let comment = b"action=0 reason=bogus-answer";
+        assert!(file.windows(comment.len()).any(|window| window == comment));
+    }
+}
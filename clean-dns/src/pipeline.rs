@@ -0,0 +1,92 @@
+use clean_dns_common::{reason_str, EVENT_FLAG_MATCHED, EVENT_FLAG_TRUNCATED};
+use std::{net::Ipv4Addr, time::UNIX_EPOCH};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::{self, JoinHandle},
+};
+
+use crate::events::Event;
+
+// events waiting for the sinks before the readers block
+pub const QUEUE_LEN: usize = 4096;
+
+/// Consumes the events read from the kernel.
+pub trait Sink: Send {
+    fn handle(&mut self, event: &Event) -> Result<(), anyhow::Error>;
+
+    /// Called once when exiting, after the last event.
+    fn finish(&mut self) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
+
+/// The running pipeline, to be stopped when exiting.
+pub struct Pipeline {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl Pipeline {
+    /// Finish every sink and wait for them.
+    pub async fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.task.await;
+    }
+}
+
+/// Pass every event received on `events` to each of the `sinks`, in order.
+pub fn run(mut events: mpsc::Receiver<Event>, mut sinks: Vec<Box<dyn Sink>>) -> Pipeline {
+    let (stop, mut stopped) = oneshot::channel();
+    let task = task::spawn(async move {
+        loop {
+            let event = tokio::select! {
+                event = events.recv() => event,
+                _ = &mut stopped => None,
+            };
+            let event = match event {
+                Some(event) => event,
+                None => break,
+            };
+            for sink in &mut sinks {
+                if let Err(e) = sink.handle(&event) {
+                    eprintln!("event sink: {:#}", e);
+                }
+            }
+        }
+        for sink in &mut sinks {
+            if let Err(e) = sink.finish() {
+                eprintln!("event sink: {:#}", e);
+            }
+        }
+    });
+    Pipeline { stop, task }
+}
+
+/// Prints a line per event.
+pub struct LogSink;
+
+impl Sink for LogSink {
+    fn handle(&mut self, event: &Event) -> Result<(), anyhow::Error> {
+        let data = &event.log;
+        let time = event
+            .received
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        println!(
+            "LOG: TIME {}.{:06}, SRC {}, DST {}, ACTION {}, REASON {}, TTL {}, IP TTL {}, MATCHED {}, RTT {}us, TRUNCATED {}, SUPPRESSED {}",
+            time.as_secs(),
+            time.subsec_micros(),
+            Ipv4Addr::from(data.ipv4_src_addr),
+            Ipv4Addr::from(data.ipv4_dst_addr),
+            data.action,
+            reason_str(data.reason),
+            data.answer_ttl,
+            data.ip_ttl,
+            data.flags & EVENT_FLAG_MATCHED != 0,
+            data.rtt_us,
+            data.flags & EVENT_FLAG_TRUNCATED != 0,
+            data.suppressed
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,230 @@
+use anyhow::{anyhow, Context as _};
+use clean_dns_common::{
+    TtlRange, UpstreamPolicy, MAX_TTL_RANGES, POLICY_0X20, POLICY_ANSWER_NAME, POLICY_ANSWER_TTL,
+    POLICY_ANSWER_TYPE, POLICY_AUTHORITATIVE, POLICY_COOKIES, POLICY_DEFAULT, POLICY_DONT_FRAGMENT,
+    POLICY_DROP_DUPLICATES, POLICY_IP_ID_ZERO, POLICY_IP_TTL, POLICY_MIN_RTT, POLICY_REQUIRE_EDNS,
+    POLICY_TCP_RST, POLICY_TRUNCATE, POLICY_UNSOLICITED,
+};
+use std::{fmt, net::Ipv4Addr};
+
+const CHECKS: &[(&str, u32)] = &[
+    ("ip-id", POLICY_IP_ID_ZERO),
+    ("df", POLICY_DONT_FRAGMENT),
+    ("aa", POLICY_AUTHORITATIVE),
+    ("answer-type", POLICY_ANSWER_TYPE),
+    ("answer-name", POLICY_ANSWER_NAME),
+    ("edns", POLICY_REQUIRE_EDNS),
+    ("unsolicited", POLICY_UNSOLICITED),
+    ("duplicates", POLICY_DROP_DUPLICATES),
+    ("0x20", POLICY_0X20),
+    ("cookies", POLICY_COOKIES),
+    ("truncate", POLICY_TRUNCATE),
+    ("tcp-rst", POLICY_TCP_RST),
+];
+
+/// Checks relying on the queries recorded (or rewritten) by the egress program.
+pub const EGRESS_CHECKS: u32 = POLICY_UNSOLICITED
+    | POLICY_DROP_DUPLICATES
+    | POLICY_MIN_RTT
+    | POLICY_0X20
+    | POLICY_COOKIES
+    | POLICY_REQUIRE_EDNS;
+
+/// An upstream and the checks applied to its responses.
+///
+/// Written as `ADDR[,CHECK...]`, e.g. `8.8.8.8,default,answer-type,ttl=0-10`.
+/// Without any check the default ones (`ip-id`, `df`, `aa`) are enabled, `none`
+/// disables them all.
+#[derive(Debug, Clone, Copy)]
+pub struct UpstreamSpec {
+    pub addr: Ipv4Addr,
+    pub policy: UpstreamPolicy,
+}
+
+impl UpstreamSpec {
+    pub fn new(addr: Ipv4Addr) -> Self {
+        UpstreamSpec {
+            addr,
+            policy: UpstreamPolicy::new(POLICY_DEFAULT),
+        }
+    }
+
+    fn add_ttl_range(&mut self, range: TtlSpec) -> Result<(), anyhow::Error> {
+        let count = self.policy.ttl_range_count as usize;
+        if count == MAX_TTL_RANGES {
+            return Err(anyhow!(
+                "at most {} ttl ranges are supported",
+                MAX_TTL_RANGES
+            ));
+        }
+        self.policy.ttl_ranges[count] = range.0;
+        self.policy.ttl_range_count += 1;
+        self.policy.flags |= POLICY_ANSWER_TTL;
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for UpstreamSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',');
+        let addr = parts.next().unwrap_or_default();
+        let mut spec = UpstreamSpec::new(
+            addr.parse()
+                .with_context(|| format!("invalid upstream address `{}`", addr))?,
+        );
+        let mut checks = parts.peekable();
+        if checks.peek().is_some() {
+            spec.policy.flags = 0;
+        }
+        for check in checks {
+            if let Some(range) = check.strip_prefix("ip-ttl=") {
+                spec.policy.ip_ttl = range.parse::<TtlSpec>()?.0;
+                spec.policy.flags |= POLICY_IP_TTL;
+                continue;
+            }
+            if let Some(rtt) = check.strip_prefix("min-rtt=") {
+                spec.policy.min_rtt_us = parse_rtt(rtt)?;
+                spec.policy.flags |= POLICY_MIN_RTT;
+                continue;
+            }
+            if let Some(range) = check.strip_prefix("ttl=") {
+                spec.add_ttl_range(range.parse()?)?;
+                continue;
+            }
+            spec.policy.flags |= match check {
+                "default" => POLICY_DEFAULT,
+                "none" => 0,
+                check => CHECKS
+                    .iter()
+                    .find(|(name, _)| *name == check)
+                    .map(|(_, flag)| *flag)
+                    .ok_or_else(|| anyhow!("unknown check `{}`", check))?,
+            };
+        }
+        Ok(spec)
+    }
+}
+
+/// Parse a duration written as `<N>us` or `<N>ms` (the default) into microseconds.
+fn parse_rtt(s: &str) -> Result<u32, anyhow::Error> {
+    let (value, scale) = if let Some(us) = s.strip_suffix("us") {
+        (us, 1)
+    } else {
+        (s.strip_suffix("ms").unwrap_or(s), 1000)
+    };
+    value
+        .parse::<u32>()
+        .ok()
+        .and_then(|value| value.checked_mul(scale))
+        .ok_or_else(|| anyhow!("invalid rtt `{}`", s))
+}
+
+/// A TTL range written as `MIN-MAX`, or a single TTL.
+struct TtlSpec(TtlRange);
+
+impl fmt::Display for TtlSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.min == self.0.max {
+            write!(f, "{}", self.0.min)
+        } else {
+            write!(f, "{}-{}", self.0.min, self.0.max)
+        }
+    }
+}
+
+impl std::str::FromStr for TtlSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (min, max) = s.split_once('-').unwrap_or((s, s));
+        match (min.parse(), max.parse()) {
+            (Ok(min), Ok(max)) if min <= max => Ok(TtlSpec(TtlRange { min, max })),
+            _ => Err(anyhow!("invalid ttl range `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for UpstreamSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.addr)?;
+        if self.policy.flags == 0 {
+            return write!(f, ",none");
+        }
+        for (name, flag) in CHECKS {
+            if self.policy.flags & flag != 0 {
+                write!(f, ",{}", name)?;
+            }
+        }
+        if self.policy.flags & POLICY_IP_TTL != 0 {
+            write!(f, ",ip-ttl={}", TtlSpec(self.policy.ip_ttl))?;
+        }
+        if self.policy.flags & POLICY_MIN_RTT != 0 {
+            write!(f, ",min-rtt={}us", self.policy.min_rtt_us)?;
+        }
+        for range in &self.policy.ttl_ranges[..self.policy.ttl_range_count as usize] {
+            write!(f, ",ttl={}", TtlSpec(*range))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(s: &str) -> String {
+        s.parse::<UpstreamSpec>().unwrap().to_string()
+    }
+
+    #[test]
+    fn spec_round_trip() {
+        assert_eq!(round_trip("8.8.8.8"), "8.8.8.8,ip-id,df,aa");
+        assert_eq!(round_trip("8.8.8.8,default"), "8.8.8.8,ip-id,df,aa");
+        assert_eq!(round_trip("8.8.8.8,none"), "8.8.8.8,none");
+        let spec = "1.1.1.1,ip-id,answer-type,edns,0x20,cookies,ip-ttl=50-60,min-rtt=1500us,ttl=0-10,ttl=42";
+        assert_eq!(round_trip(spec), spec);
+        // what is printed parses back into the same policy
+        let printed = round_trip("1.1.1.1,unsolicited,min-rtt=2,ttl=7-7");
+        assert_eq!(printed, "1.1.1.1,unsolicited,min-rtt=2000us,ttl=7");
+        assert_eq!(round_trip(&printed), printed);
+    }
+
+    #[test]
+    fn spec_errors() {
+        assert!("8.8.8".parse::<UpstreamSpec>().is_err());
+        assert!("8.8.8.8,bogus".parse::<UpstreamSpec>().is_err());
+        assert!("8.8.8.8,ttl=1,ttl=2,ttl=3,ttl=4"
+            .parse::<UpstreamSpec>()
+            .is_ok());
+        assert!("8.8.8.8,ttl=1,ttl=2,ttl=3,ttl=4,ttl=5"
+            .parse::<UpstreamSpec>()
+            .is_err());
+    }
+
+    #[test]
+    fn rtt() {
+        assert_eq!(parse_rtt("5").unwrap(), 5000);
+        assert_eq!(parse_rtt("5ms").unwrap(), 5000);
+        assert_eq!(parse_rtt("250us").unwrap(), 250);
+        assert!(parse_rtt("").is_err());
+        assert!(parse_rtt("5s").is_err());
+        assert!(parse_rtt("-1ms").is_err());
+        // overflows the microseconds
+        assert!(parse_rtt("5000000ms").is_err());
+    }
+
+    #[test]
+    fn ttl_spec() {
+        let range = "10-20".parse::<TtlSpec>().unwrap();
+        assert_eq!((range.0.min, range.0.max), (10, 20));
+        assert_eq!(range.to_string(), "10-20");
+        let single = "42".parse::<TtlSpec>().unwrap();
+        assert_eq!((single.0.min, single.0.max), (42, 42));
+        assert_eq!(single.to_string(), "42");
+        assert!("20-10".parse::<TtlSpec>().is_err());
+        assert!("10-".parse::<TtlSpec>().is_err());
+        assert!("x".parse::<TtlSpec>().is_err());
+    }
+}
@@ -0,0 +1,120 @@
+use anyhow::bail;
+use aya::programs::{Xdp, XdpFlags};
+use std::{fmt::Write as _, fs, mem, path::Path};
+
+const BPF_MAP_CREATE: libc::c_long = 0;
+const BPF_MAP_TYPE_LPM_TRIE: u32 = 11;
+const BPF_F_NO_PREALLOC: u32 = 1;
+
+// bits of the effective capability set, see capability.h
+const CAPABILITIES: &[(&str, u32)] = &[
+    ("CAP_NET_ADMIN", 12),
+    ("CAP_SYS_ADMIN", 21),
+    ("CAP_PERFMON", 38),
+    ("CAP_BPF", 39),
+];
+
+/// What the running kernel and process can do, probed before loading anything.
+#[derive(Debug)]
+pub struct Features {
+    pub kernel: String,
+    pub btf: bool,
+    pub capabilities: Vec<(&'static str, bool)>,
+    pub lpm_trie: bool,
+}
+
+impl Features {
+    pub fn probe() -> Self {
+        let kernel = fs::read_to_string("/proc/sys/kernel/osrelease")
+            .map(|release| release.trim().to_string())
+            .unwrap_or_else(|_| "unknown".to_string());
+        let effective = effective_capabilities().unwrap_or(0);
+        Features {
+            kernel,
+            btf: Path::new("/sys/kernel/btf/vmlinux").exists(),
+            capabilities: CAPABILITIES
+                .iter()
+                .map(|(name, bit)| (*name, effective & (1 << bit) != 0))
+                .collect(),
+            // u32 prefix length followed by an IPv4 address
+            lpm_trie: can_create_map(BPF_MAP_TYPE_LPM_TRIE, 8, 4, 1, BPF_F_NO_PREALLOC),
+        }
+    }
+
+    fn has(&self, capability: &str) -> bool {
+        self.capabilities
+            .iter()
+            .any(|(name, present)| *name == capability && *present)
+    }
+
+    /// Fail with a readable message when the filter can't run at all.
+    pub fn check(&self) -> Result<(), anyhow::Error> {
+        if !self.has("CAP_NET_ADMIN") {
+            bail!("CAP_NET_ADMIN is required to attach to the interface, run as root");
+        }
+        // before CAP_BPF and CAP_PERFMON, loading programs needed CAP_SYS_ADMIN
+        if !self.has("CAP_SYS_ADMIN") && !(self.has("CAP_BPF") && self.has("CAP_PERFMON")) {
+            bail!("CAP_SYS_ADMIN (or CAP_BPF and CAP_PERFMON) is required to load the filter");
+        }
+        Ok(())
+    }
+
+    pub fn report(&self) -> String {
+        let yes_no = |present: bool| if present { "yes" } else { "no" };
+        let mut report = String::new();
+        let _ = writeln!(report, "kernel: {}", self.kernel);
+        let _ = writeln!(report, "BTF: {}", yes_no(self.btf));
+        for (name, present) in &self.capabilities {
+            let _ = writeln!(report, "{}: {}", name, yes_no(*present));
+        }
+        let _ = writeln!(report, "LPM trie maps: {}", yes_no(self.lpm_trie));
+        report
+    }
+}
+
+/// Attach `program` in driver mode, falling back to the generic SKB mode for
+/// drivers without XDP support. Returns the mode it got attached in.
+pub fn attach_xdp(program: &mut Xdp, iface: &str) -> Result<&'static str, anyhow::Error> {
+    match program.attach(iface, XdpFlags::DRV_MODE) {
+        Ok(_) => Ok("driver"),
+        Err(e) => {
+            eprintln!(
+                "native XDP unavailable on {} ({}), using SKB mode",
+                iface, e
+            );
+            program.attach(iface, XdpFlags::SKB_MODE)?;
+            Ok("skb")
+        }
+    }
+}
+
+fn effective_capabilities() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("CapEff:"))?;
+    u64::from_str_radix(line["CapEff:".len()..].trim(), 16).ok()
+}
+
+fn can_create_map(
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    flags: u32,
+) -> bool {
+    // the leading fields of the BPF_MAP_CREATE bpf_attr, the rest zeroed
+    let mut attr = [0u32; 30];
+    attr[..5].copy_from_slice(&[map_type, key_size, value_size, max_entries, flags]);
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            BPF_MAP_CREATE,
+            attr.as_ptr(),
+            mem::size_of_val(&attr),
+        )
+    };
+    if fd < 0 {
+        return false;
+    }
+    unsafe { libc::close(fd as libc::c_int) };
+    true
+}
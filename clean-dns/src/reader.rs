@@ -0,0 +1,170 @@
+use aya::maps::{perf::AsyncPerfEventArray, MapRefMut};
+use bytes::BytesMut;
+use clean_dns_common::reason_str;
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{sync::mpsc, task, time};
+
+use crate::events::{self, Event};
+
+// a reader that keeps failing is retried at this pace
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// How the per-cpu perf buffers are read.
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    /// buffers filled per read
+    pub buffers: usize,
+    /// initial capacity of each buffer
+    pub buffer_size: usize,
+    /// pages of each perf ring, the kernel default when `None`
+    pub pages: Option<usize>,
+}
+
+#[derive(Debug, Default)]
+struct CpuStats {
+    read: AtomicU64,
+    lost: AtomicU64,
+    restarts: AtomicU64,
+}
+
+/// Events read and lost per cpu, since the reader started.
+#[derive(Debug, Default)]
+pub struct EventStats {
+    cpus: BTreeMap<u32, CpuStats>,
+    /// events the kernel suppressed per reason, as reported by the next event
+    suppressed: Mutex<BTreeMap<u32, u64>>,
+}
+
+impl EventStats {
+    /// Render the counters in the Prometheus text format.
+    pub fn metrics(&self, out: &mut String) {
+        let counters: [(&str, &str, fn(&CpuStats) -> &AtomicU64); 3] = [
+            (
+                "clean_dns_events_read_total",
+                "Events read from the perf buffers",
+                |s| &s.read,
+            ),
+            (
+                "clean_dns_events_lost_total",
+                "Events the perf buffers overwrote",
+                |s| &s.lost,
+            ),
+            (
+                "clean_dns_reader_restarts_total",
+                "Perf readers restarted after an error",
+                |s| &s.restarts,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            for (cpu, stats) in &self.cpus {
+                let value = counter(stats).load(Ordering::Relaxed);
+                let _ = writeln!(out, "{}{{cpu=\"{}\"}} {}", name, cpu, value);
+            }
+        }
+        let name = "clean_dns_events_suppressed_total";
+        let _ = writeln!(out, "# HELP {} Events over the event rate", name);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        for (reason, count) in self.suppressed.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "{}{{reason=\"{}\"}} {}",
+                name,
+                reason_str(*reason),
+                count
+            );
+        }
+    }
+}
+
+/// Read the events of `cpus` from `perf_array`, sending each decoded one to
+/// `sink`. A reader failing is restarted instead of giving up on its cpu.
+pub fn spawn(
+    perf_array: AsyncPerfEventArray<MapRefMut>,
+    cpus: &[u32],
+    options: Options,
+    sink: mpsc::Sender<Event>,
+) -> Arc<EventStats> {
+    let stats = Arc::new(EventStats {
+        cpus: cpus.iter().map(|cpu| (*cpu, CpuStats::default())).collect(),
+        suppressed: Mutex::default(),
+    });
+    let perf_array = Arc::new(Mutex::new(perf_array));
+    for &cpu_id in cpus {
+        let perf_array = perf_array.clone();
+        let stats = stats.clone();
+        let sink = sink.clone();
+        task::spawn(async move {
+            let cpu_stats = &stats.cpus[&cpu_id];
+            let mut warned = false;
+            loop {
+                let opened = perf_array.lock().unwrap().open(cpu_id, options.pages);
+                let mut buf = match opened {
+                    Ok(buf) => buf,
+                    Err(e) => {
+                        eprintln!("failed to open the perf buffer of cpu {}: {}", cpu_id, e);
+                        cpu_stats.restarts.fetch_add(1, Ordering::Relaxed);
+                        time::sleep(RESTART_DELAY).await;
+                        continue;
+                    }
+                };
+                let mut buffers = (0..options.buffers)
+                    .map(|_| BytesMut::with_capacity(options.buffer_size))
+                    .collect::<Vec<_>>();
+                loop {
+                    let events = match buf.read_events(&mut buffers).await {
+                        Ok(events) => events,
+                        Err(e) => {
+                            eprintln!("perf reader of cpu {} failed, restarting: {}", cpu_id, e);
+                            break;
+                        }
+                    };
+                    cpu_stats
+                        .read
+                        .fetch_add(events.read as u64, Ordering::Relaxed);
+                    if events.lost > 0 {
+                        cpu_stats
+                            .lost
+                            .fetch_add(events.lost as u64, Ordering::Relaxed);
+                        eprintln!("lost {} events on cpu {}", events.lost, cpu_id);
+                    }
+                    for buffer in buffers.iter().take(events.read) {
+                        match events::decode_event(buffer) {
+                            Ok(event) => {
+                                let data = &event.log;
+                                if data.suppressed > 0 {
+                                    *stats
+                                        .suppressed
+                                        .lock()
+                                        .unwrap()
+                                        .entry(data.reason)
+                                        .or_default() += data.suppressed as u64;
+                                }
+                                // the pipeline only goes away when exiting
+                                let _ = sink.send(event).await;
+                            }
+                            // every event of a mismatched object fails the same way
+                            Err(e) if !warned => {
+                                eprintln!("dropping undecodable events: {:#}", e);
+                                warned = true;
+                            }
+                            Err(_) => {}
+                        }
+                    }
+                }
+                cpu_stats.restarts.fetch_add(1, Ordering::Relaxed);
+                time::sleep(RESTART_DELAY).await;
+            }
+        });
+    }
+    stats
+}
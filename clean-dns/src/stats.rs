@@ -0,0 +1,44 @@
+use aya::maps::{MapRef, PerCpuHashMap};
+use clean_dns_common::IpTtlKey;
+use std::{collections::BTreeMap, fmt::Write as _, net::Ipv4Addr};
+
+/// Sum the per-cpu `IP_TTL_STATS` counters into a TTL histogram per upstream.
+pub fn ip_ttl_histograms(
+    stats: &PerCpuHashMap<MapRef, IpTtlKey, u64>,
+) -> Result<BTreeMap<Ipv4Addr, BTreeMap<u8, u64>>, anyhow::Error> {
+    let mut histograms = BTreeMap::<_, BTreeMap<_, _>>::new();
+    for entry in stats.iter() {
+        let (key, values) = entry?;
+        let count: u64 = values.iter().sum();
+        *histograms
+            .entry(Ipv4Addr::from(key.upstream))
+            .or_default()
+            .entry(key.ttl as u8)
+            .or_default() += count;
+    }
+    Ok(histograms)
+}
+
+/// Render the observed IP TTL distribution of each upstream.
+pub fn ip_ttl_report(
+    stats: &PerCpuHashMap<MapRef, IpTtlKey, u64>,
+) -> Result<String, anyhow::Error> {
+    let mut report = String::new();
+    for (upstream, histogram) in ip_ttl_histograms(stats)? {
+        let total: u64 = histogram.values().sum();
+        writeln!(report, "{} ({} responses)", upstream, total)?;
+        for (ttl, count) in histogram {
+            writeln!(
+                report,
+                "  ttl {:>3}: {:>10} {:>6.2}%",
+                ttl,
+                count,
+                count as f64 * 100.0 / total as f64
+            )?;
+        }
+    }
+    if report.is_empty() {
+        report.push_str("no responses observed yet\n");
+    }
+    Ok(report)
+}
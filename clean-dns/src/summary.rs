@@ -0,0 +1,165 @@
+use clean_dns_common::{reason_str, REASON_NONE};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    net::Ipv4Addr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{events::Event, pipeline::Sink};
+
+// domains listed in each summary
+const TOP_DOMAINS: usize = 10;
+
+#[derive(Debug, Default)]
+struct Counts {
+    responses: u64,
+    dropped: u64,
+}
+
+impl Counts {
+    fn add(&mut self, responses: u64, dropped: bool) {
+        self.responses += responses;
+        if dropped {
+            self.dropped += responses;
+        }
+    }
+}
+
+/// What the events since the last summary added up to.
+#[derive(Debug, Default)]
+struct Window {
+    total: Counts,
+    upstreams: BTreeMap<Ipv4Addr, Counts>,
+    reasons: BTreeMap<u32, u64>,
+    /// dropped responses per question name, when the frame was captured
+    domains: HashMap<String, u64>,
+}
+
+/// Merges the events of every cpu into periodic summaries.
+#[derive(Debug, Default)]
+pub struct Summarizer {
+    window: Mutex<Window>,
+}
+
+impl Summarizer {
+    /// Render the events since the previous summary, `interval` ago, and
+    /// start a new window.
+    pub fn summary(&self, interval: Duration) -> String {
+        let window = std::mem::take(&mut *self.window.lock().unwrap());
+        let secs = interval.as_secs_f64().max(1.0);
+        let share = |counts: &Counts| {
+            if counts.responses == 0 {
+                0.0
+            } else {
+                counts.dropped as f64 * 100.0 / counts.responses as f64
+            }
+        };
+        let mut summary = String::new();
+        let _ = writeln!(
+            summary,
+            "SUMMARY: {} responses, {} dropped ({:.2}%, {:.1}/s) in the last {}s",
+            window.total.responses,
+            window.total.dropped,
+            share(&window.total),
+            window.total.dropped as f64 / secs,
+            interval.as_secs()
+        );
+        for (upstream, counts) in &window.upstreams {
+            let _ = writeln!(
+                summary,
+                "  upstream {}: {} responses, {} dropped ({:.2}%)",
+                upstream,
+                counts.responses,
+                counts.dropped,
+                share(counts)
+            );
+        }
+        for (reason, count) in &window.reasons {
+            let _ = writeln!(summary, "  reason {}: {}", reason_str(*reason), count);
+        }
+        let mut domains = window.domains.into_iter().collect::<Vec<_>>();
+        domains.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        for (domain, count) in domains.into_iter().take(TOP_DOMAINS) {
+            let _ = writeln!(summary, "  domain {}: {}", domain, count);
+        }
+        summary
+    }
+}
+
+impl Sink for Arc<Summarizer> {
+    fn handle(&mut self, event: &Event) -> Result<(), anyhow::Error> {
+        let log = &event.log;
+        let dropped = log.reason != REASON_NONE;
+        // the suppressed events shared this one's upstream and reason
+        let responses = 1 + log.suppressed as u64;
+        let mut window = self.window.lock().unwrap();
+        window.total.add(responses, dropped);
+        window
+            .upstreams
+            .entry(Ipv4Addr::from(log.ipv4_src_addr))
+            .or_default()
+            .add(responses, dropped);
+        if dropped {
+            *window.reasons.entry(log.reason).or_default() += responses;
+            // the names of the suppressed ones weren't captured
+            if let Some(qname) = event.qname() {
+                *window.domains.entry(qname).or_default() += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::tests::question;
+    use clean_dns_common::{REASON_BOGUS_ANSWER, REASON_IP_ID_ZERO};
+
+    fn response(upstream: Ipv4Addr, reason: u32, suppressed: u32, name: &[u8]) -> Event {
+        let mut event = question(name);
+        event.log.ipv4_src_addr = u32::from(upstream);
+        event.log.reason = reason;
+        event.log.suppressed = suppressed;
+        event
+    }
+
+    #[test]
+    fn summary() {
+        let mut summarizer = Arc::new(Summarizer::default());
+        let google = Ipv4Addr::new(8, 8, 8, 8);
+        let cloudflare = Ipv4Addr::new(1, 1, 1, 1);
+        let name = b"\x07example\x03com\x00";
+        for _ in 0..3 {
+            summarizer
+                .handle(&response(google, REASON_NONE, 0, name))
+                .unwrap();
+        }
+        // one more response of the same upstream and reason was suppressed
+        let mut bogus = response(google, REASON_BOGUS_ANSWER, 1, name);
+        summarizer.handle(&bogus).unwrap();
+        // the question of a response wasn't captured
+        bogus.frame.clear();
+        summarizer.handle(&bogus).unwrap();
+        let ip_id = response(cloudflare, REASON_IP_ID_ZERO, 0, b"\x01a\x00");
+        summarizer.handle(&ip_id).unwrap();
+
+        assert_eq!(
+            summarizer.summary(Duration::from_secs(10)),
+            "SUMMARY: 8 responses, 5 dropped (62.50%, 0.5/s) in the last 10s\n\
+             \x20 upstream 1.1.1.1: 1 responses, 1 dropped (100.00%)\n\
+             \x20 upstream 8.8.8.8: 7 responses, 4 dropped (57.14%)\n\
+             \x20 reason ip-id-zero: 1\n\
+             \x20 reason bogus-answer: 4\n\
+             \x20 domain a: 1\n\
+             \x20 domain example.com: 1\n"
+        );
+        // every summary starts a new window
+        assert_eq!(
+            summarizer.summary(Duration::from_secs(10)),
+            "SUMMARY: 0 responses, 0 dropped (0.00%, 0.0/s) in the last 10s\n"
+        );
+    }
+}
@@ -60,5 +60,19 @@ pub fn build_ebpf(opts: Options) -> Result<(), anyhow::Error> {
         .status()
         .expect("failed to build bpf program");
     assert!(status.success());
+    // the conntrack variant only loads on kernels with the conntrack kfuncs,
+    // it is built next to the plain one so userspace can fall back
+    args.extend([
+        "--features",
+        "conntrack",
+        "--target-dir",
+        "../target/conntrack",
+    ]);
+    let status = Command::new("cargo")
+        .current_dir(&dir)
+        .args(&args)
+        .status()
+        .expect("failed to build conntrack bpf program");
+    assert!(status.success());
     Ok(())
 }